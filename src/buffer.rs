@@ -3,19 +3,35 @@ use lua_shared::lua_State;
 use paste::paste;
 use std::u64;
 
-use crate::{check_slice, insert_function};
+use crate::compress;
+use crate::crypto::Cipher;
+use crate::encoding;
+use crate::gmod;
+use crate::hash;
+use crate::lua_struct;
+use crate::serialize;
+use crate::{check_slice, insert_function, struct_try};
 macro_rules! def_rw {
     ($type_name:ty) => {
         paste! {
             fn [<read_ $type_name>](&mut self) -> Option<$type_name> {
-                if self.0.len() == 0 || self.1 + std::mem::size_of::<$type_name>() > self.0.len() {
+                let size = std::mem::size_of::<$type_name>();
+                if self.data.len() == 0 || self.pos + size > self.data.len() {
                     return None
                 }
-                let data = self.read(std::mem::size_of::<$type_name>())?;
-                unsafe {Some($type_name::from_le(*std::mem::transmute::<_, &$type_name>(data.as_ptr())))}
+                let data = self.read(size)?.try_into().ok()?;
+                if self.big_endian {
+                    Some($type_name::from_be_bytes(data))
+                } else {
+                    Some($type_name::from_le_bytes(data))
+                }
             }
             fn [<write_ $type_name>](&mut self, value: $type_name) {
-                self.write(&value.to_le_bytes());
+                if self.big_endian {
+                    self.write(&value.to_be_bytes());
+                } else {
+                    self.write(&value.to_le_bytes());
+                }
             }
         }
     };
@@ -80,82 +96,266 @@ macro_rules! def_lmfloat {
     };
 }
 
-pub struct Buffer(Vec<u8>, usize);
+// 64-bit integers don't fit into lua numbers, so they are passed either
+// as decimal strings, or as low and high 32-bit halves.
+macro_rules! def_lm64 {
+    ($name:ident, $type_name:ty, $half:ty) => {
+        paste! {
+            fn [<lm_read_ $name>](state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+                unsafe {
+                    let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+                    let halves = lua::toboolean(state, 2) as u8 != 0;
+                    match this.[<read_ $type_name>]() {
+                        Some(val) if halves => {
+                            lua::pushnumber(state, val as u32 as _);
+                            lua::pushnumber(state, (val >> 32) as $half as _);
+                            return Ok(2);
+                        }
+                        Some(val) => {
+                            let str = val.to_string();
+                            lua::pushlstring(state, str.as_ptr(), str.len());
+                        }
+                        None => lua::pushnil(state),
+                    }
+                    Ok(1)
+                }
+            }
+            fn [<lm_write_ $name>](state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+                unsafe {
+                    let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+                    let value = match lua::get_type(state, 2) {
+                        4 => std::str::from_utf8(check_slice!(state, 2))
+                            .ok()
+                            .and_then(|str| str.trim().parse::<$type_name>().ok()),
+                        _ if lua::get_type(state, 3) > 0 => {
+                            let lo = lua::Lchecknumber(state, 2);
+                            let hi = lua::Lchecknumber(state, 3);
+                            let fits = |value: f64, min: f64, max: f64| {
+                                value.fract() == 0.0 && value >= min && value <= max
+                            };
+                            if fits(lo, 0.0, u32::MAX as f64)
+                                && fits(hi, <$half>::MIN as f64, <$half>::MAX as f64)
+                            {
+                                Some(((hi as $half as u64) << 32 | lo as u64) as $type_name)
+                            } else {
+                                None
+                            }
+                        }
+                        _ => {
+                            let value = lua::Lchecknumber(state, 2);
+                            if <$type_name>::MIN == 0 && value < 0.0 {
+                                lua::Largerror(
+                                    state,
+                                    2,
+                                    lua::cstr!("unsigned value can't be negative"),
+                                );
+                            }
+                            // Anything above 2^53 has already lost precision.
+                            if value.fract() == 0.0 && value.abs() <= (1u64 << 53) as f64 {
+                                Some(value as $type_name)
+                            } else {
+                                None
+                            }
+                        }
+                    };
+                    match value {
+                        Some(value) => this.[<write_ $type_name>](value),
+                        None => lua::Largerror(
+                            state,
+                            2,
+                            lua::cstr!("integer string or lo, hi numbers expected"),
+                        ),
+                    }
+                    Ok(0)
+                }
+            }
+        }
+    };
+}
+
+pub struct Buffer {
+    data: Vec<u8>,
+    // Read/write position.
+    pos: usize,
+    // Whether numbers are big-endian.
+    big_endian: bool,
+    // Amount of bits used in the byte before position.
+    bits: u8,
+}
 
 impl Buffer {
     fn new(size: usize) -> Self {
-        Self(Vec::with_capacity(size), 0)
+        Self {
+            data: Vec::with_capacity(size),
+            pos: 0,
+            big_endian: false,
+            bits: 0,
+        }
+    }
+
+    /// Moves position, partially read or written byte is left behind.
+    fn set_pos(&mut self, pos: usize) {
+        self.pos = pos;
+        self.bits = 0;
     }
 
     fn read(&mut self, bytes: usize) -> Option<&[u8]> {
-        if bytes == 0 || self.0.len() == 0 {
+        self.bits = 0;
+        if bytes == 0 || self.data.len() == 0 {
             return None;
         }
-        let pos = self.1.min(self.0.len());
-        let bytes_to_read = if bytes + pos > self.0.len() {
-            self.0.len() - pos
+        let pos = self.pos.min(self.data.len());
+        let bytes_to_read = if bytes + pos > self.data.len() {
+            self.data.len() - pos
         } else {
             bytes
         };
         if bytes_to_read == 0 {
             return None;
         }
-        self.1 += bytes_to_read;
-        Some(&self.0[pos..pos + bytes_to_read])
+        self.pos += bytes_to_read;
+        Some(&self.data[pos..pos + bytes_to_read])
     }
 
     // Straightforward ripoff from Cursor.
     fn write(&mut self, data: &[u8]) {
-        if self.1 > self.0.len() {
+        self.bits = 0;
+        if self.pos > self.data.len() {
             return;
         }
-        let len = self.0.len();
-        if len < self.1 {
-            self.0.resize(self.1, 0);
+        let len = self.data.len();
+        if len < self.pos {
+            self.data.resize(self.pos, 0);
         }
         {
-            let space = self.0.len() - self.1;
+            let space = self.data.len() - self.pos;
             let (left, right) = data.split_at(std::cmp::min(space, data.len()));
-            self.0[self.1..self.1 + left.len()].copy_from_slice(left);
-            self.0.extend_from_slice(right);
+            self.data[self.pos..self.pos + left.len()].copy_from_slice(left);
+            self.data.extend_from_slice(right);
         }
-        self.1 += data.len();
+        self.pos += data.len();
     }
 
-    fn read_f32(&mut self) -> Option<f32> {
-        if self.0.len() == 0 || self.1 + 4 > self.0.len() {
+    def_rw!(u8 u16 i16 u32 i32 u64 i64);
+
+    // Replaces `len` bytes at `pos` with `data`, returns removed bytes.
+    // Position after the edited range keeps pointing at the same data.
+    fn splice(&mut self, pos: usize, len: usize, data: &[u8]) -> Vec<u8> {
+        let pos = pos.min(self.data.len());
+        let end = pos.saturating_add(len).min(self.data.len());
+        let removed: Vec<u8> = self.data.splice(pos..end, data.iter().copied()).collect();
+        if self.pos >= end {
+            // Partially read byte is the one before position, it's gone or no longer before it.
+            if self.pos == end && (end > pos || !data.is_empty()) {
+                self.bits = 0;
+            }
+            self.pos = self.pos - removed.len() + data.len();
+        } else if self.pos > pos {
+            self.set_pos(pos + data.len());
+        }
+        removed
+    }
+
+    // Bits are packed least significant first, same as bf_write does it for the net library.
+    // Byte reads and writes skip the rest of partially used byte.
+    fn bits_left(&self) -> usize {
+        let partial = if self.bits == 0 {
+            0
+        } else {
+            8 - self.bits as usize
+        };
+        partial + self.data.len().saturating_sub(self.pos) * 8
+    }
+
+    fn read_bit(&mut self) -> Option<bool> {
+        if self.bits == 0 {
+            if self.pos >= self.data.len() {
+                return None;
+            }
+            self.pos += 1;
+        }
+        let bit = self.data[self.pos - 1] >> self.bits & 1;
+        self.bits = (self.bits + 1) % 8;
+        Some(bit != 0)
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        if self.bits == 0 {
+            if self.pos >= self.data.len() {
+                self.write(&[0]);
+            } else {
+                self.pos += 1;
+            }
+        }
+        let byte = &mut self.data[self.pos - 1];
+        if bit {
+            *byte |= 1 << self.bits;
+        } else {
+            *byte &= !(1 << self.bits);
+        }
+        self.bits = (self.bits + 1) % 8;
+    }
+
+    fn read_bits(&mut self, bits: u8) -> Option<u64> {
+        if self.bits_left() < bits as usize {
             return None;
         }
-        let data = self.read(4)?;
-        unsafe {
-            Some(f32::from_bits(u32::from_le(
-                *std::mem::transmute::<_, &u32>(data.as_ptr()),
-            )))
+        let mut value = 0;
+        for i in 0..bits {
+            value |= (self.read_bit()? as u64) << i;
+        }
+        Some(value)
+    }
+
+    fn write_bits(&mut self, value: u64, bits: u8) {
+        for i in 0..bits {
+            self.write_bit(value >> i & 1 != 0);
         }
     }
 
+    fn read_f32(&mut self) -> Option<f32> {
+        self.read_u32().map(f32::from_bits)
+    }
+
     fn write_f32(&mut self, value: f32) {
-        self.write(&value.to_le_bytes());
+        self.write_u32(value.to_bits());
     }
 
     fn read_f64(&mut self) -> Option<f64> {
-        if self.0.len() == 0 || self.1 + 8 > self.0.len() {
+        self.read_u64().map(f64::from_bits)
+    }
+
+    fn write_f64(&mut self, value: f64) {
+        self.write_u64(value.to_bits());
+    }
+
+    // Vectors and angles are stored as 3 floats, or 3 doubles if `double` is set.
+    fn read_components(&mut self, double: bool) -> Option<[f64; 3]> {
+        let size = if double { 24 } else { 12 };
+        if self.pos + size > self.data.len() {
             return None;
         }
-        let data = self.read(8)?;
-        unsafe {
-            Some(f64::from_bits(u64::from_le(
-                *std::mem::transmute::<_, &u64>(data.as_ptr()),
-            )))
+        let mut value = [0.0; 3];
+        for component in value.iter_mut() {
+            *component = if double {
+                self.read_f64()?
+            } else {
+                self.read_f32()? as f64
+            };
         }
+        Some(value)
     }
 
-    fn write_f64(&mut self, value: f64) {
-        self.write(&value.to_le_bytes());
+    fn write_components(&mut self, value: [f64; 3], double: bool) {
+        for component in value {
+            if double {
+                self.write_f64(component);
+            } else {
+                self.write_f32(component as f32);
+            }
+        }
     }
 
-    def_rw!(u8 u16 i16 u32 i32);
-
     fn __gc(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
         unsafe {
             lua::Lcheckudata(state, 1, lua::cstr!("cslb"))
@@ -186,6 +386,116 @@ impl Buffer {
         }
     }
 
+    unsafe fn check_prefix(state: lua_State, index: i32) -> Option<usize> {
+        if lua::get_type(state, index) <= 0 {
+            return None;
+        }
+        match check_slice!(state, index) {
+            b"varint" => None,
+            b"u8" => Some(1),
+            b"u16" => Some(2),
+            b"u32" => Some(4),
+            _ => lua::Largerror(
+                state,
+                index,
+                lua::cstr!("expected \"varint\", \"u8\", \"u16\" or \"u32\""),
+            ),
+        }
+    }
+
+    // Length prefix is varint unless its size in bytes is given.
+    fn read_string(&mut self, prefix: Option<usize>) -> Option<&[u8]> {
+        let start = (self.pos, self.bits);
+        let len = match prefix {
+            Some(1) => self.read_u8().map(|len| len as usize),
+            Some(2) => self.read_u16().map(|len| len as usize),
+            Some(_) => self.read_u32().map(|len| len as usize),
+            None => {
+                let pos = self.pos.min(self.data.len());
+                let (len, size) = lua_struct::read_varint(&self.data[pos..])?;
+                self.set_pos(pos + size);
+                usize::try_from(len).ok()
+            }
+        };
+        match len {
+            Some(len) if self.pos + len <= self.data.len() => {
+                self.pos += len;
+                Some(&self.data[self.pos - len..self.pos])
+            }
+            _ => {
+                (self.pos, self.bits) = start;
+                None
+            }
+        }
+    }
+
+    fn write_string(&mut self, data: &[u8], prefix: Option<usize>) -> bool {
+        match prefix {
+            Some(1) if data.len() <= u8::MAX as usize => self.write_u8(data.len() as _),
+            Some(2) if data.len() <= u16::MAX as usize => self.write_u16(data.len() as _),
+            Some(4) if data.len() <= u32::MAX as usize => self.write_u32(data.len() as _),
+            None => {
+                let mut varint = [0; lua_struct::MAX_VARINT_SIZE];
+                self.write(lua_struct::write_varint(data.len() as _, &mut varint));
+            }
+            _ => return false,
+        }
+        self.write(data);
+        true
+    }
+
+    fn lm_read_string(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let prefix = Self::check_prefix(state, 2);
+            match this.read_string(prefix) {
+                Some(val) => lua::pushlstring(state, val.as_ptr(), val.len()),
+                None => lua::pushnil(state),
+            }
+            Ok(1)
+        }
+    }
+
+    fn lm_write_string(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let data = check_slice!(state, 2);
+            let prefix = Self::check_prefix(state, 3);
+            if !this.write_string(data, prefix) {
+                lua::Largerror(state, 2, lua::cstr!("string is too long for length prefix"));
+            }
+            Ok(0)
+        }
+    }
+
+    fn lm_read_cstring(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let pos = this.pos.min(this.data.len());
+            match this.data[pos..].iter().position(|byte| *byte == 0) {
+                Some(len) => {
+                    this.set_pos(pos + len + 1);
+                    lua::pushlstring(state, this.data.as_ptr().add(pos), len);
+                }
+                None => lua::pushnil(state),
+            }
+            Ok(1)
+        }
+    }
+
+    fn lm_write_cstring(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let data = check_slice!(state, 2);
+            if data.contains(&0) {
+                lua::Largerror(state, 2, lua::cstr!("string contains zeros"));
+            }
+            this.write(data);
+            this.write(&[0]);
+            Ok(0)
+        }
+    }
+
     fn lm_read_bool(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
         unsafe {
             let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
@@ -212,30 +522,347 @@ impl Buffer {
     def_lmint!(short, i16);
     def_lmint!(ulong, u32);
     def_lmint!(long, i32);
+    def_lm64!(uint64, u64, u32);
+    def_lm64!(int64, i64, i32);
     def_lmfloat!(float, f32);
     def_lmfloat!(double, f64);
 
+    fn lm_read_vector(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            match this.read_components(lua::toboolean(state, 2) as u8 != 0) {
+                Some(value) => gmod::push_vector(state, value),
+                None => lua::pushnil(state),
+            }
+            Ok(1)
+        }
+    }
+
+    fn lm_write_vector(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let value = gmod::check_vector(state, 2);
+            this.write_components(value, lua::toboolean(state, 3) as u8 != 0);
+            Ok(0)
+        }
+    }
+
+    fn lm_read_angle(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            match this.read_components(lua::toboolean(state, 2) as u8 != 0) {
+                Some(value) => gmod::push_angle(state, value),
+                None => lua::pushnil(state),
+            }
+            Ok(1)
+        }
+    }
+
+    fn lm_write_angle(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let value = gmod::check_angle(state, 2);
+            this.write_components(value, lua::toboolean(state, 3) as u8 != 0);
+            Ok(0)
+        }
+    }
+
+    fn lm_read_color(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            if this.pos + 4 > this.data.len() {
+                lua::pushnil(state);
+                return Ok(1);
+            }
+            match this.read(4) {
+                Some(&[r, g, b, a]) => gmod::push_color(state, [r, g, b, a]),
+                _ => lua::pushnil(state),
+            }
+            Ok(1)
+        }
+    }
+
+    fn lm_write_color(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            this.write(&gmod::check_color(state, 2));
+            Ok(0)
+        }
+    }
+
+    fn lm_write_struct(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let fmt = struct_try!(state, lua_struct::check_struct(state, 2));
+            let data = struct_try!(state, lua_struct::pack_at(state, &fmt, 3, this.pos));
+            this.write(data);
+            Ok(0)
+        }
+    }
+
+    fn lm_read_struct(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let fmt = struct_try!(state, lua_struct::check_struct(state, 2));
+            let pos = this.pos.min(this.data.len());
+            let (nrets, end) =
+                struct_try!(state, lua_struct::unpack_at(state, &fmt, &this.data, pos));
+            this.set_pos(end);
+            Ok(nrets)
+        }
+    }
+
+    fn lm_read_bit(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            match this.read_bit() {
+                Some(bit) => lua::pushinteger(state, bit as _),
+                None => lua::pushnil(state),
+            }
+            Ok(1)
+        }
+    }
+
+    fn lm_write_bit(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let bit = match lua::get_type(state, 2) {
+                1 => lua::toboolean(state, 2) as u8 != 0,
+                _ => lua::Lcheckinteger(state, 2) != 0,
+            };
+            this.write_bit(bit);
+            Ok(0)
+        }
+    }
+
+    unsafe fn check_bits(state: lua_State, index: i32) -> u8 {
+        match lua::Lcheckinteger(state, index) {
+            bits @ 1..=32 => bits as u8,
+            _ => lua::Largerror(state, index, lua::cstr!("bit count out of range [1,32]")),
+        }
+    }
+
+    fn lm_read_uint(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let bits = Self::check_bits(state, 2);
+            match this.read_bits(bits) {
+                Some(value) => lua::pushnumber(state, value as _),
+                None => lua::pushnil(state),
+            }
+            Ok(1)
+        }
+    }
+
+    fn lm_write_uint(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let value = lua::Lchecknumber(state, 2) as u64;
+            let bits = Self::check_bits(state, 3);
+            this.write_bits(value, bits);
+            Ok(0)
+        }
+    }
+
+    fn lm_read_int(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let bits = Self::check_bits(state, 2);
+            match this.read_bits(bits) {
+                Some(value) => {
+                    let shift = 64 - bits as u32;
+                    lua::pushnumber(state, ((value << shift) as i64 >> shift) as _);
+                }
+                None => lua::pushnil(state),
+            }
+            Ok(1)
+        }
+    }
+
+    fn lm_write_int(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let value = lua::Lchecknumber(state, 2) as i64;
+            let bits = Self::check_bits(state, 3);
+            this.write_bits(value as u64, bits);
+            Ok(0)
+        }
+    }
+
+    fn lm_compress(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let algorithm = compress::check_algorithm(state, 2);
+            this.data = compress::compress(&this.data, algorithm, compress::opt_level(state, 3))?;
+            this.set_pos(0);
+            Ok(0)
+        }
+    }
+
+    fn lm_decompress(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            this.data = compress::decompress(&this.data)?;
+            this.set_pos(0);
+            Ok(0)
+        }
+    }
+
+    // Optional associated data isn't stored, `Decrypt` must be given the same.
+    unsafe fn opt_aad<'a>(state: lua_State, index: i32) -> &'a [u8] {
+        let mut len = 0;
+        std::slice::from_raw_parts(
+            lua::Loptlstring(state, index, lua::cstr!(""), &mut len),
+            len,
+        )
+    }
+
+    /// `Encrypt(secret[, aad])`
+    fn lm_encrypt(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let aad = Self::opt_aad(state, 3);
+            this.data = Cipher::new(check_slice!(state, 2)).encrypt(&this.data, aad)?;
+            this.set_pos(0);
+            Ok(0)
+        }
+    }
+
+    /// `Decrypt(secret[, aad])`, fails if data was changed or secret or aad differ.
+    fn lm_decrypt(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let aad = Self::opt_aad(state, 3);
+            this.data = Cipher::new(check_slice!(state, 2)).decrypt(&this.data, aad)?;
+            this.set_pos(0);
+            Ok(0)
+        }
+    }
+
+    fn lm_hash(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let digest = hash::digest(hash::check_algorithm(state, 2), &this.data);
+            hash::push_digest(state, &digest, lua::toboolean(state, 3) as u8 != 0);
+            Ok(1)
+        }
+    }
+
+    fn lm_to_hex(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let str = hex::encode(&this.data);
+            lua::pushlstring(state, str.as_ptr(), str.len());
+            Ok(1)
+        }
+    }
+
+    fn lm_from_hex(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            this.data = hex::decode(check_slice!(state, 2))?;
+            this.set_pos(0);
+            Ok(0)
+        }
+    }
+
+    fn lm_to_base64(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let str = encoding::to_base64(&this.data, lua::toboolean(state, 2) as u8 != 0);
+            lua::pushlstring(state, str.as_ptr(), str.len());
+            Ok(1)
+        }
+    }
+
+    fn lm_from_base64(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let data = check_slice!(state, 2);
+            this.data = encoding::from_base64(data, lua::toboolean(state, 3) as u8 != 0)?;
+            this.set_pos(0);
+            Ok(0)
+        }
+    }
+
+    fn lm_write_value(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let mut data = Vec::new();
+            serialize::write_value(state, 2, &mut data)?;
+            this.write(&data);
+            Ok(0)
+        }
+    }
+
+    fn lm_read_value(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let pos = this.pos.min(this.data.len());
+            let consumed = serialize::read_value(state, &this.data[pos..])?;
+            this.set_pos(pos + consumed);
+            Ok(1)
+        }
+    }
+
     fn lm_tell(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
         unsafe {
             let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
-            lua::pushinteger(state, this.1 as _);
+            lua::pushinteger(state, this.pos as _);
             Ok(1)
         }
     }
 
+    /// `Seek(offset[, whence])`, whence is "set" (default), "cur" or "end".
+    /// Resulting position is clamped to [0, size], returns it.
     fn lm_seek(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
         unsafe {
             let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
-            this.1 = (lua::Lcheckinteger(state, 2).max(0) as usize).min(this.0.len() - 1);
+            let offset = lua::Lcheckinteger(state, 2) as i64;
+            let base = if lua::get_type(state, 3) > 0 {
+                match check_slice!(state, 3) {
+                    b"set" => 0,
+                    b"cur" => this.pos as i64,
+                    b"end" => this.data.len() as i64,
+                    _ => {
+                        lua::Largerror(state, 3, lua::cstr!("expected \"set\", \"cur\" or \"end\""))
+                    }
+                }
+            } else {
+                0
+            };
+            this.set_pos(base.saturating_add(offset).clamp(0, this.data.len() as i64) as usize);
+            lua::pushinteger(state, this.pos as _);
+            Ok(1)
+        }
+    }
+
+    fn lm_set_endian(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            this.big_endian = match check_slice!(state, 2) {
+                b"big" => true,
+                b"little" => false,
+                _ => lua::Largerror(state, 2, lua::cstr!("expected \"big\" or \"little\"")),
+            };
             Ok(0)
         }
     }
 
+    fn lm_get_endian(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let endian = if this.big_endian { "big" } else { "little" };
+            lua::pushlstring(state, endian.as_ptr(), endian.len());
+            Ok(1)
+        }
+    }
+
     fn lm_clear(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
         unsafe {
             let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
-            this.0.clear();
-            this.1 = 0;
+            this.data.clear();
+            this.set_pos(0);
             Ok(0)
         }
     }
@@ -244,8 +871,10 @@ impl Buffer {
         unsafe {
             let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
             let size = lua::Lcheckinteger(state, 2).max(0) as usize;
-            this.0.resize(size, 0);
-            this.1 = this.1.min(size);
+            this.data.resize(size, 0);
+            if this.pos > size {
+                this.set_pos(size);
+            }
             Ok(0)
         }
     }
@@ -253,7 +882,7 @@ impl Buffer {
     fn lm_shrink(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
         unsafe {
             let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
-            this.0.shrink_to_fit();
+            this.data.shrink_to_fit();
             Ok(1)
         }
     }
@@ -261,7 +890,185 @@ impl Buffer {
     fn lm_get_value(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
         unsafe {
             let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
-            lua::pushlstring(state, this.0.as_ptr(), this.0.len());
+            lua::pushlstring(state, this.data.as_ptr(), this.data.len());
+            Ok(1)
+        }
+    }
+
+    fn lm_peek(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let bytes = lua::Lcheckinteger(state, 2).max(0) as usize;
+            let pos = this.pos.min(this.data.len());
+            let end = (pos + bytes).min(this.data.len());
+            if end > pos {
+                lua::pushlstring(state, this.data.as_ptr().add(pos), end - pos);
+            } else {
+                lua::pushnil(state);
+            }
+            Ok(1)
+        }
+    }
+
+    /// `Sub([start[, end]])`, copies bytes in [start, end) to a new buffer.
+    /// Positions are 0-based like `Tell` and `Seek`, negative ones count from the end.
+    fn lm_sub(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &*lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let end = if lua::get_type(state, 3) > 0 {
+                Some(lua::Lcheckinteger(state, 3) as i64)
+            } else {
+                None
+            };
+            let range = sub_range(this.data.len(), lua::Loptinteger(state, 2, 0) as i64, end);
+            let mut buffer = Self::new(range.len());
+            buffer.big_endian = this.big_endian;
+            buffer.data.extend_from_slice(&this.data[range]);
+            Self::push(state, buffer);
+            Ok(1)
+        }
+    }
+
+    fn lm_size(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            lua::pushinteger(state, this.data.len() as _);
+            Ok(1)
+        }
+    }
+
+    fn lm_remaining(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            lua::pushinteger(state, this.data.len().saturating_sub(this.pos) as _);
+            Ok(1)
+        }
+    }
+
+    fn lm_capacity(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            lua::pushinteger(state, this.data.capacity() as _);
+            Ok(1)
+        }
+    }
+
+    fn lm_reserve(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            // Reported to lua, `reserve` would panic or abort on a huge size.
+            this.data
+                .try_reserve(lua::Lcheckinteger(state, 2).max(0) as usize)?;
+            Ok(0)
+        }
+    }
+
+    fn __tostring(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let str = format!("Buffer [{} bytes, pos {}]", this.data.len(), this.pos);
+            lua::pushlstring(state, str.as_ptr(), str.len());
+            Ok(1)
+        }
+    }
+
+    fn __eq(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let lhs = &*lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let rhs = &*lua::Lcheckudata(state, 2, lua::cstr!("cslb")).cast::<Self>();
+            lua::pushboolean(state, (lhs.data == rhs.data) as _);
+            Ok(1)
+        }
+    }
+
+    // Either operand may be a string, result is always a new buffer.
+    fn __concat(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let lhs = check_bytes(state, 1);
+            let rhs = check_bytes(state, 2);
+            let mut buffer = Self::new(lhs.len() + rhs.len());
+            buffer.data.extend_from_slice(lhs);
+            buffer.data.extend_from_slice(rhs);
+            Self::push(state, buffer);
+            Ok(1)
+        }
+    }
+
+    unsafe fn check_pos(state: lua_State, index: i32) -> usize {
+        lua::Lcheckinteger(state, index).max(0) as usize
+    }
+
+    fn lm_insert(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            // Copied before borrowing this buffer, since data may come from it.
+            let data = check_bytes(state, 3).to_vec();
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let pos = Self::check_pos(state, 2);
+            this.splice(pos, 0, &data);
+            Ok(0)
+        }
+    }
+
+    fn lm_delete(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let pos = Self::check_pos(state, 2);
+            let removed = this.splice(pos, Self::check_pos(state, 3), &[]);
+            lua::pushinteger(state, removed.len() as _);
+            Ok(1)
+        }
+    }
+
+    /// `Splice(pos, n, data)`, returns removed bytes.
+    fn lm_splice(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            // Copied before borrowing this buffer, since data may come from it.
+            let data = check_bytes(state, 4).to_vec();
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let pos = Self::check_pos(state, 2);
+            let len = Self::check_pos(state, 3);
+            let removed = this.splice(pos, len, &data);
+            lua::pushlstring(state, removed.as_ptr(), removed.len());
+            Ok(1)
+        }
+    }
+
+    /// `Fill(byte, n)` writes `n` copies of `byte` at the current position.
+    fn lm_fill(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let byte = lua::Lcheckinteger(state, 2) as u8;
+            let len = lua::Lcheckinteger(state, 3);
+            if len < 0 {
+                lua::Largerror(state, 3, lua::cstr!("count can't be negative"));
+            }
+            // Allocation failures are reported to lua instead of aborting.
+            let mut data = Vec::new();
+            data.try_reserve_exact(len as usize)?;
+            data.resize(len as usize, byte);
+            this.data.try_reserve(len as usize)?;
+            this.write(&data);
+            Ok(0)
+        }
+    }
+
+    /// `Find(needle[, start])`, returns position of the first match or nil.
+    fn lm_find(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &*lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
+            let needle = check_bytes(state, 2);
+            let start = (lua::Loptinteger(state, 3, 0).max(0) as usize).min(this.data.len());
+            let found = if needle.is_empty() {
+                Some(0)
+            } else {
+                this.data[start..]
+                    .windows(needle.len())
+                    .position(|window| window == needle)
+            };
+            match found {
+                Some(pos) => lua::pushinteger(state, (start + pos) as _),
+                None => lua::pushnil(state),
+            }
             Ok(1)
         }
     }
@@ -270,9 +1077,9 @@ impl Buffer {
         unsafe {
             let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslb")).cast::<Self>();
             let data = check_slice!(state, 2);
-            this.0.clear();
-            this.1 = 0;
-            this.0.extend_from_slice(data);
+            this.data.clear();
+            this.set_pos(0);
+            this.data.extend_from_slice(data);
             Ok(0)
         }
     }
@@ -283,31 +1090,83 @@ impl Buffer {
                 lua::pushvalue(state, -1);
                 lua::setfield(state, -2, lua::cstr!("__index"));
                 insert_function!(state, "__gc", Self::__gc);
+                insert_function!(state, "__len", Self::lm_size);
+                insert_function!(state, "__tostring", Self::__tostring);
+                insert_function!(state, "__eq", Self::__eq);
+                insert_function!(state, "__concat", Self::__concat);
                 insert_function!(state, "Read", Self::lm_read);
+                insert_function!(state, "Peek", Self::lm_peek);
+                insert_function!(state, "ReadString", Self::lm_read_string);
+                insert_function!(state, "ReadCString", Self::lm_read_cstring);
                 insert_function!(state, "ReadBool", Self::lm_read_bool);
                 insert_function!(state, "ReadByte", Self::lm_read_byte);
                 insert_function!(state, "ReadUShort", Self::lm_read_ushort);
                 insert_function!(state, "ReadShort", Self::lm_read_short);
                 insert_function!(state, "ReadULong", Self::lm_read_ulong);
                 insert_function!(state, "ReadLong", Self::lm_read_long);
+                insert_function!(state, "ReadUInt64", Self::lm_read_uint64);
+                insert_function!(state, "ReadInt64", Self::lm_read_int64);
                 insert_function!(state, "ReadFloat", Self::lm_read_float);
                 insert_function!(state, "ReadDouble", Self::lm_read_double);
+                insert_function!(state, "ReadVector", Self::lm_read_vector);
+                insert_function!(state, "ReadAngle", Self::lm_read_angle);
+                insert_function!(state, "ReadColor", Self::lm_read_color);
+                insert_function!(state, "ReadStruct", Self::lm_read_struct);
+                insert_function!(state, "ReadValue", Self::lm_read_value);
+                insert_function!(state, "ReadBit", Self::lm_read_bit);
+                insert_function!(state, "ReadUInt", Self::lm_read_uint);
+                insert_function!(state, "ReadInt", Self::lm_read_int);
 
                 insert_function!(state, "Write", Self::lm_write);
+                insert_function!(state, "WriteString", Self::lm_write_string);
+                insert_function!(state, "WriteCString", Self::lm_write_cstring);
                 insert_function!(state, "WriteBool", Self::lm_write_bool);
                 insert_function!(state, "WriteByte", Self::lm_write_byte);
                 insert_function!(state, "WriteUShort", Self::lm_write_ushort);
                 insert_function!(state, "WriteShort", Self::lm_write_short);
                 insert_function!(state, "WriteULong", Self::lm_write_ulong);
                 insert_function!(state, "WriteLong", Self::lm_write_long);
+                insert_function!(state, "WriteUInt64", Self::lm_write_uint64);
+                insert_function!(state, "WriteInt64", Self::lm_write_int64);
                 insert_function!(state, "WriteFloat", Self::lm_write_float);
                 insert_function!(state, "WriteDouble", Self::lm_write_double);
+                insert_function!(state, "WriteVector", Self::lm_write_vector);
+                insert_function!(state, "WriteAngle", Self::lm_write_angle);
+                insert_function!(state, "WriteColor", Self::lm_write_color);
+                insert_function!(state, "WriteStruct", Self::lm_write_struct);
+                insert_function!(state, "WriteValue", Self::lm_write_value);
+                insert_function!(state, "WriteBit", Self::lm_write_bit);
+                insert_function!(state, "WriteUInt", Self::lm_write_uint);
+                insert_function!(state, "WriteInt", Self::lm_write_int);
+
+                insert_function!(state, "Compress", Self::lm_compress);
+                insert_function!(state, "Decompress", Self::lm_decompress);
+
+                insert_function!(state, "Encrypt", Self::lm_encrypt);
+                insert_function!(state, "Decrypt", Self::lm_decrypt);
+                insert_function!(state, "Hash", Self::lm_hash);
+                insert_function!(state, "ToHex", Self::lm_to_hex);
+                insert_function!(state, "FromHex", Self::lm_from_hex);
+                insert_function!(state, "ToBase64", Self::lm_to_base64);
+                insert_function!(state, "FromBase64", Self::lm_from_base64);
 
                 insert_function!(state, "Tell", Self::lm_tell);
                 insert_function!(state, "Seek", Self::lm_seek);
                 insert_function!(state, "Clear", Self::lm_clear);
+                insert_function!(state, "SetEndian", Self::lm_set_endian);
+                insert_function!(state, "GetEndian", Self::lm_get_endian);
                 insert_function!(state, "Resize", Self::lm_resize);
                 insert_function!(state, "Shrink", Self::lm_shrink);
+                insert_function!(state, "Reserve", Self::lm_reserve);
+                insert_function!(state, "Size", Self::lm_size);
+                insert_function!(state, "Remaining", Self::lm_remaining);
+                insert_function!(state, "Capacity", Self::lm_capacity);
+                insert_function!(state, "Sub", Self::lm_sub);
+                insert_function!(state, "Insert", Self::lm_insert);
+                insert_function!(state, "Delete", Self::lm_delete);
+                insert_function!(state, "Splice", Self::lm_splice);
+                insert_function!(state, "Fill", Self::lm_fill);
+                insert_function!(state, "Find", Self::lm_find);
 
                 insert_function!(state, "GetValue", Self::lm_get_value);
                 insert_function!(state, "SetValue", Self::lm_set_value);
@@ -322,16 +1181,152 @@ impl Buffer {
                 4 => {
                     let data = check_slice!(state, 1);
                     let mut buffer = Self::new(data.len());
-                    buffer.0.extend_from_slice(data);
+                    buffer.data.extend_from_slice(data);
                     buffer
                 }
                 _ => Self::new(0),
             };
-            let udata = lua::newuserdata(state, std::mem::size_of::<Self>()).cast::<Self>();
-            udata.write(buffer);
-            Self::metatable(state);
-            lua::setmetatable(state, -2);
+            Self::push(state, buffer);
         }
         Ok(1)
     }
+
+    unsafe fn push(state: lua_State, buffer: Self) {
+        let udata = lua::newuserdata(state, std::mem::size_of::<Self>()).cast::<Self>();
+        udata.write(buffer);
+        Self::metatable(state);
+        lua::setmetatable(state, -2);
+    }
+
+    /// Returns `Buffer` at `index` if there is one.
+    /// Shared, since the same buffer may also be another argument.
+    pub unsafe fn test<'a>(state: lua_State, index: i32) -> Option<&'a Self> {
+        if crate::test_metatable(state, index, lua::cstr!("cslb")) {
+            Some(&*lua::touserdata(state, index).cast::<Self>())
+        } else {
+            None
+        }
+    }
+
+    /// Replaces contents of `Buffer` at `index` with `data` and pushes it,
+    /// pushes a new `Buffer` if there is none. Existing allocation is reused.
+    pub unsafe fn fill_or_push(state: lua_State, index: i32, data: &[u8]) {
+        if lua::get_type(state, index) <= 0 {
+            let mut buffer = Self::new(data.len());
+            buffer.data.extend_from_slice(data);
+            return Self::push(state, buffer);
+        }
+        let this = &mut *lua::Lcheckudata(state, index, lua::cstr!("cslb")).cast::<Self>();
+        this.data.clear();
+        this.data.extend_from_slice(data);
+        this.set_pos(0);
+        lua::pushvalue(state, index);
+    }
+}
+
+// Range of `Sub`, end defaults to the size.
+fn sub_range(len: usize, start: i64, end: Option<i64>) -> std::ops::Range<usize> {
+    let index = |index: i64| {
+        let index = if index < 0 { len as i64 + index } else { index };
+        index.clamp(0, len as i64) as usize
+    };
+    let start = index(start);
+    let end = end.map_or(len, index);
+    start..end.max(start)
+}
+
+/// Contents of `buffer`, if they decode cleanly with `fmt`.
+pub fn check_struct_data<'a>(
+    fmt: &lua_struct::Struct,
+    buffer: &'a Buffer,
+) -> Result<&'a [u8], Box<dyn std::error::Error>> {
+    match lua_struct::validate(fmt, &buffer.data) {
+        Some(e) => Err(format!("buffer doesn't match the format: {}", e).into()),
+        None => Ok(&buffer.data),
+    }
+}
+
+/// Contents of `Buffer` or string at `index`.
+pub unsafe fn check_bytes<'a>(state: lua_State, index: i32) -> &'a [u8] {
+    match Buffer::test(state, index) {
+        Some(buffer) => &buffer.data,
+        None => check_slice!(state, index),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_bits(data: &[u8], pos: usize, bits: u8) -> Buffer {
+        let mut buffer = Buffer::new(data.len());
+        buffer.data.extend_from_slice(data);
+        buffer.pos = pos;
+        buffer.bits = bits;
+        buffer
+    }
+
+    #[test]
+    fn sub_range_is_zero_based() {
+        assert_eq!(sub_range(5, 0, None), 0..5);
+        assert_eq!(sub_range(5, 1, Some(3)), 1..3);
+        assert_eq!(sub_range(5, -2, None), 3..5);
+        assert_eq!(sub_range(5, 0, Some(-1)), 0..4);
+        assert_eq!(sub_range(5, -10, Some(10)), 0..5);
+        assert_eq!(sub_range(5, 4, Some(2)), 4..4);
+        assert_eq!(sub_range(5, 7, None), 5..5);
+        assert_eq!(sub_range(0, 0, None), 0..0);
+    }
+
+    #[test]
+    fn splice_keeps_bits_of_moved_byte() {
+        let mut buffer = with_bits(&[1, 2, 0b101, 4], 3, 2);
+        buffer.splice(0, 1, &[9, 9, 9]);
+        assert_eq!((buffer.pos, buffer.bits), (5, 2));
+        assert_eq!(buffer.read_bit(), Some(true));
+
+        let mut buffer = with_bits(&[1, 2, 0b101, 4], 3, 2);
+        buffer.splice(3, 1, &[]);
+        assert_eq!((buffer.pos, buffer.bits), (3, 2));
+        assert_eq!(buffer.read_bit(), Some(true));
+
+        let mut buffer = with_bits(&[1, 2, 0b101, 4], 3, 2);
+        buffer.splice(3, 0, &[]);
+        assert_eq!((buffer.pos, buffer.bits), (3, 2));
+    }
+
+    #[test]
+    fn splice_resets_bits_of_replaced_byte() {
+        let mut buffer = with_bits(&[1, 2, 3, 4], 3, 2);
+        buffer.splice(3, 0, &[9]);
+        assert_eq!((buffer.pos, buffer.bits), (4, 0));
+
+        let mut buffer = with_bits(&[1, 2, 3, 4], 3, 2);
+        buffer.splice(2, 1, &[]);
+        assert_eq!((buffer.pos, buffer.bits), (2, 0));
+
+        let mut buffer = with_bits(&[1, 2, 3, 4], 3, 2);
+        buffer.splice(1, 2, &[9, 9, 9]);
+        assert_eq!((buffer.pos, buffer.bits), (4, 0));
+
+        let mut buffer = with_bits(&[1, 2, 3, 4], 3, 2);
+        buffer.splice(0, 4, &[]);
+        assert_eq!((buffer.pos, buffer.bits), (0, 0));
+    }
+
+    #[test]
+    fn bits_after_splice() {
+        let mut buffer = Buffer::new(0);
+        buffer.write_bit(true);
+        buffer.write_bit(false);
+        buffer.splice(1, 0, &[0xFF]);
+        buffer.write_bit(true);
+        assert_eq!(buffer.data, [0b01, 0xFF, 0b1]);
+
+        buffer.set_pos(0);
+        assert_eq!(buffer.read_bit(), Some(true));
+        buffer.splice(0, 0, &[7]);
+        assert_eq!(buffer.read_bit(), Some(false));
+        assert_eq!(buffer.bits_left(), 22);
+    }
 }
@@ -0,0 +1,464 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::Transactional;
+
+use crate::compress;
+use crate::crypto::Cipher;
+
+// Settings of every tree with a codec, keyed by tree name.
+// Created when the first tree gets a codec.
+const SETTINGS_TREE: &str = "__lsled_codecs";
+const KEY_CHECK_SIZE: usize = 32;
+
+/// Transforms applied to values on `Insert` and undone on `Get`.
+/// Settings are stored in the database and apply to every value in the tree,
+/// changing them rewrites values that are already there.
+/// Secret of an encrypted tree is not stored, until it's given values can't be read or written.
+#[derive(Debug, Clone)]
+pub struct Codec {
+    db: sled::Db,
+    name: sled::IVec,
+    compression: Option<(compress::Algorithm, Option<i32>)>,
+    key_check: Option<[u8; KEY_CHECK_SIZE]>,
+    encryption: Option<Cipher>,
+}
+
+fn invalid_settings() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid codec settings")
+}
+
+fn locked() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "tree is encrypted, call SetEncryption with its secret first",
+    )
+}
+
+/// Whether `name` is used internally and can't be opened or dropped from lua.
+pub fn is_reserved(name: &[u8]) -> bool {
+    name == SETTINGS_TREE.as_bytes()
+}
+
+fn find_settings(db: &sled::Db) -> sled::Result<Option<sled::Tree>> {
+    if db.tree_names().iter().any(|name| is_reserved(name)) {
+        Ok(Some(db.open_tree(SETTINGS_TREE)?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Codec used by every handle of one tree, so settings changed through one apply to all.
+#[derive(Debug, Clone)]
+pub struct SharedCodec(Arc<RwLock<Codec>>);
+
+impl SharedCodec {
+    /// Current settings. A copy, so lua can be called while using it.
+    pub fn get(&self) -> Codec {
+        self.0
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub fn set_compression(
+        &self,
+        tree: &sled::Tree,
+        compression: Option<(compress::Algorithm, Option<i32>)>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut codec = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        codec.set_compression(tree, compression)
+    }
+
+    pub fn set_encryption(
+        &self,
+        tree: &sled::Tree,
+        encryption: Option<Cipher>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut codec = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        codec.set_encryption(tree, encryption)
+    }
+}
+
+/// Codecs of trees opened from one database, by tree name.
+#[derive(Debug, Clone, Default)]
+pub struct Codecs(Arc<Mutex<HashMap<sled::IVec, SharedCodec>>>);
+
+impl Codecs {
+    /// Codec of the tree called `name`, settings are loaded when it's first asked for.
+    pub fn get(
+        &self,
+        db: &sled::Db,
+        name: sled::IVec,
+    ) -> Result<SharedCodec, Box<dyn std::error::Error>> {
+        let mut codecs = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(codec) = codecs.get(&name) {
+            return Ok(codec.clone());
+        }
+        let codec = SharedCodec(Arc::new(RwLock::new(Codec::open(db, name.clone())?)));
+        codecs.insert(name, codec.clone());
+        Ok(codec)
+    }
+
+    /// Forgets codec and settings of a dropped tree.
+    pub fn drop_tree(&self, db: &sled::Db, name: &[u8]) -> sled::Result<()> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(name);
+        if let Some(settings) = find_settings(db)? {
+            settings.remove(name)?;
+        }
+        Ok(())
+    }
+}
+
+impl Codec {
+    fn open(db: &sled::Db, name: sled::IVec) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut codec = Self {
+            db: db.clone(),
+            name,
+            compression: None,
+            key_check: None,
+            encryption: None,
+        };
+        if let Some(settings) = find_settings(db)? {
+            if let Some(data) = settings.get(&codec.name)? {
+                codec.load(&data)?;
+            }
+        }
+        Ok(codec)
+    }
+
+    // Algorithm tag or 0, whether level is set, level as i32,
+    // then whether values are encrypted, followed by the key check if they are.
+    fn load(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.len() < 7 {
+            return Err(invalid_settings());
+        }
+        self.compression = match data[0] {
+            0 => None,
+            tag => {
+                let algorithm = compress::Algorithm::from_tag(tag).ok_or_else(invalid_settings)?;
+                let level = i32::from_le_bytes(data[2..6].try_into().unwrap());
+                Some((algorithm, if data[1] != 0 { Some(level) } else { None }))
+            }
+        };
+        self.key_check = match (data[6], &data[7..]) {
+            (0, []) => None,
+            (1, check) => Some(check.try_into().map_err(|_| invalid_settings())?),
+            _ => return Err(invalid_settings()),
+        };
+        Ok(())
+    }
+
+    fn store(&self) -> Option<Vec<u8>> {
+        if self.compression.is_none() && self.key_check.is_none() {
+            return None;
+        }
+        let (algorithm, level) = match self.compression {
+            Some((algorithm, level)) => (algorithm as u8, level),
+            None => (0, None),
+        };
+        let mut data = vec![algorithm, level.is_some() as u8];
+        data.extend_from_slice(&level.unwrap_or(0).to_le_bytes());
+        data.push(self.key_check.is_some() as u8);
+        if let Some(ref check) = self.key_check {
+            data.extend_from_slice(check);
+        }
+        Some(data)
+    }
+
+    // Tree name and key, so encrypted values can't be moved to another key or tree.
+    fn aad(&self, key: &[u8]) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(4 + self.name.len() + key.len());
+        aad.extend_from_slice(&(self.name.len() as u32).to_le_bytes());
+        aad.extend_from_slice(&self.name);
+        aad.extend_from_slice(key);
+        aad
+    }
+
+    fn cipher(&self) -> io::Result<Option<&Cipher>> {
+        match (&self.encryption, self.key_check) {
+            (None, Some(_)) => Err(locked()),
+            (cipher, _) => Ok(cipher.as_ref()),
+        }
+    }
+
+    // Values are compressed first, encrypted data doesn't compress.
+    pub fn encode<'a>(&self, key: &[u8], value: &'a [u8]) -> io::Result<Cow<'a, [u8]>> {
+        let cipher = self.cipher()?;
+        let value = match self.compression {
+            Some((algorithm, level)) => Cow::Owned(compress::compress(value, algorithm, level)?),
+            None => Cow::Borrowed(value),
+        };
+        match cipher {
+            Some(cipher) => Ok(Cow::Owned(cipher.encrypt(&value, &self.aad(key))?)),
+            None => Ok(value),
+        }
+    }
+
+    pub fn decode(&self, key: &[u8], value: sled::IVec) -> io::Result<sled::IVec> {
+        let value = match self.cipher()? {
+            Some(cipher) => cipher.decrypt(&value, &self.aad(key))?.into(),
+            None => value,
+        };
+        match self.compression {
+            Some(_) => Ok(compress::decompress(&value)?.into()),
+            None => Ok(value),
+        }
+    }
+
+    pub fn pop_max(
+        &self,
+        tree: &sled::Tree,
+    ) -> Result<Option<(sled::IVec, sled::IVec)>, Box<dyn std::error::Error>> {
+        self.pop(tree, true)
+    }
+
+    pub fn pop_min(
+        &self,
+        tree: &sled::Tree,
+    ) -> Result<Option<(sled::IVec, sled::IVec)>, Box<dyn std::error::Error>> {
+        self.pop(tree, false)
+    }
+
+    // Entry is decoded before it's removed, so one that can't be is left in place.
+    fn pop(
+        &self,
+        tree: &sled::Tree,
+        max: bool,
+    ) -> Result<Option<(sled::IVec, sled::IVec)>, Box<dyn std::error::Error>> {
+        loop {
+            let entry = if max { tree.last()? } else { tree.first()? };
+            let (key, stored) = match entry {
+                Some(entry) => entry,
+                None => return Ok(None),
+            };
+            let value = self.decode(&key, stored.clone())?;
+            // Someone else changed or popped it meanwhile, try the next one.
+            if tree
+                .compare_and_swap(&key, Some(&stored), None as Option<&[u8]>)?
+                .is_ok()
+            {
+                return Ok(Some((key, value)));
+            }
+        }
+    }
+
+    /// Compresses every value of `tree` with `compression`, or decompresses them with `None`.
+    fn set_compression(
+        &mut self,
+        tree: &sled::Tree,
+        compression: Option<(compress::Algorithm, Option<i32>)>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut next = self.clone();
+        next.compression = compression;
+        self.rewrite(tree, next)
+    }
+
+    /// Unlocks an encrypted tree if `encryption` has the stored key,
+    /// otherwise encrypts every value of `tree` with it, or decrypts them with `None`.
+    fn set_encryption(
+        &mut self,
+        tree: &sled::Tree,
+        encryption: Option<Cipher>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let check = encryption.as_ref().map(|cipher| *cipher.key_check());
+        if check.is_some() && check == self.key_check {
+            self.encryption = encryption;
+            return Ok(());
+        }
+        if self.encryption.is_none() && self.key_check.is_some() {
+            return Err(match check {
+                Some(_) => "secret doesn't match the one tree is encrypted with".into(),
+                None => locked().into(),
+            });
+        }
+        let mut next = self.clone();
+        next.key_check = check;
+        next.encryption = encryption;
+        self.rewrite(tree, next)
+    }
+
+    // Re-encodes all values with `next` and stores its settings, all or nothing.
+    fn rewrite(
+        &mut self,
+        tree: &sled::Tree,
+        next: Codec,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut batch = sled::Batch::default();
+        for entry in tree.iter() {
+            let (key, value) = entry?;
+            let value = next.encode(&key, &self.decode(&key, value)?)?.into_owned();
+            batch.insert(key, value);
+        }
+        let settings = next.store();
+        let settings_tree = match settings {
+            Some(_) => self.db.open_tree(SETTINGS_TREE)?,
+            None => match find_settings(&self.db)? {
+                Some(settings_tree) => settings_tree,
+                None => {
+                    tree.apply_batch(batch)?;
+                    *self = next;
+                    return Ok(());
+                }
+            },
+        };
+        let result = (tree, &settings_tree).transaction(|(tree, settings_tree)| {
+            tree.apply_batch(&batch)?;
+            match settings {
+                Some(ref settings) => {
+                    settings_tree.insert(self.name.clone(), settings.as_slice())?
+                }
+                None => settings_tree.remove(self.name.clone())?,
+            };
+            Ok::<_, ConflictableTransactionError<()>>(())
+        });
+        if let Err(TransactionError::Storage(e)) = result {
+            return Err(e.into());
+        }
+        *self = next;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open() -> (sled::Db, sled::Tree, Codec) {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree("tree").unwrap();
+        let codec = Codec::open(&db, tree.name()).unwrap();
+        (db, tree, codec)
+    }
+
+    fn get(tree: &sled::Tree, codec: &Codec, key: &[u8]) -> io::Result<sled::IVec> {
+        codec.decode(key, tree.get(key).unwrap().unwrap())
+    }
+
+    #[test]
+    fn settings_persist() {
+        let (db, tree, mut codec) = open();
+        tree.insert("a", "hello").unwrap();
+        codec
+            .set_compression(&tree, Some((compress::Algorithm::Zstd, Some(3))))
+            .unwrap();
+        codec
+            .set_encryption(&tree, Some(Cipher::new(b"secret")))
+            .unwrap();
+        assert_ne!(tree.get("a").unwrap().unwrap(), "hello");
+
+        let mut reopened = Codec::open(&db, tree.name()).unwrap();
+        assert!(get(&tree, &reopened, b"a").is_err());
+        assert!(reopened.encode(b"a", b"hello").is_err());
+        assert!(reopened
+            .set_encryption(&tree, Some(Cipher::new(b"other")))
+            .is_err());
+        reopened
+            .set_encryption(&tree, Some(Cipher::new(b"secret")))
+            .unwrap();
+        assert_eq!(get(&tree, &reopened, b"a").unwrap(), "hello");
+
+        reopened.set_encryption(&tree, None).unwrap();
+        reopened.set_compression(&tree, None).unwrap();
+        assert_eq!(tree.get("a").unwrap().unwrap(), "hello");
+        assert!(db.open_tree(SETTINGS_TREE).unwrap().is_empty());
+    }
+
+    #[test]
+    fn values_are_bound_to_key_and_tree() {
+        let (db, tree, mut codec) = open();
+        codec
+            .set_encryption(&tree, Some(Cipher::new(b"secret")))
+            .unwrap();
+        tree.insert("a", codec.encode(b"a", b"hello").unwrap().as_ref())
+            .unwrap();
+        assert_eq!(get(&tree, &codec, b"a").unwrap(), "hello");
+
+        tree.insert("b", tree.get("a").unwrap().unwrap()).unwrap();
+        assert!(get(&tree, &codec, b"b").is_err());
+
+        let other = db.open_tree("other").unwrap();
+        let mut other_codec = Codec::open(&db, other.name()).unwrap();
+        other_codec
+            .set_encryption(&other, Some(Cipher::new(b"secret")))
+            .unwrap();
+        other.insert("a", tree.get("a").unwrap().unwrap()).unwrap();
+        assert!(get(&other, &other_codec, b"a").is_err());
+    }
+
+    #[test]
+    fn pop_keeps_values_it_cant_decode() {
+        let (db, tree, mut codec) = open();
+        codec
+            .set_encryption(&tree, Some(Cipher::new(b"secret")))
+            .unwrap();
+        for key in ["a", "b"] {
+            tree.insert(
+                key,
+                codec.encode(key.as_bytes(), b"value").unwrap().as_ref(),
+            )
+            .unwrap();
+        }
+        let locked = Codec::open(&db, tree.name()).unwrap();
+        assert!(locked.pop_min(&tree).is_err());
+        assert!(locked.pop_max(&tree).is_err());
+        assert_eq!(tree.len(), 2);
+
+        let (key, value) = codec.pop_max(&tree).unwrap().unwrap();
+        assert_eq!((key.as_ref(), value.as_ref()), (&b"b"[..], &b"value"[..]));
+        assert_eq!(codec.pop_min(&tree).unwrap().unwrap().0, "a");
+        assert!(codec.pop_min(&tree).unwrap().is_none());
+    }
+
+    #[test]
+    fn settings_tree_is_created_on_demand() {
+        let (db, tree, mut codec) = open();
+        let has_settings = |db: &sled::Db| db.tree_names().iter().any(|name| is_reserved(name));
+        assert!(!has_settings(&db));
+        codec.set_compression(&tree, None).unwrap();
+        Codecs::default().drop_tree(&db, b"tree").unwrap();
+        assert!(!has_settings(&db));
+        codec
+            .set_compression(&tree, Some((compress::Algorithm::Lz4, None)))
+            .unwrap();
+        assert!(has_settings(&db));
+    }
+
+    #[test]
+    fn handles_share_codec() {
+        let (db, tree, _) = open();
+        let codecs = Codecs::default();
+        let first = codecs.get(&db, tree.name()).unwrap();
+        let second = codecs.get(&db, tree.name()).unwrap();
+        first
+            .set_compression(&tree, Some((compress::Algorithm::Lz4, None)))
+            .unwrap();
+        let value = second.get().encode(b"a", b"hello").unwrap().into_owned();
+        assert_eq!(first.get().decode(b"a", value.into()).unwrap(), "hello");
+
+        codecs.drop_tree(&db, b"tree").unwrap();
+        assert!(codecs
+            .get(&db, tree.name())
+            .unwrap()
+            .get()
+            .compression
+            .is_none());
+    }
+
+    #[test]
+    fn plain_values_are_not_sniffed() {
+        let (_db, tree, mut codec) = open();
+        let value = [0xE5; 40];
+        tree.insert("a", &value[..]).unwrap();
+        codec
+            .set_encryption(&tree, Some(Cipher::new(b"secret")))
+            .unwrap();
+        assert_eq!(get(&tree, &codec, b"a").unwrap(), value);
+    }
+}
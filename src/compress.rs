@@ -0,0 +1,177 @@
+use std::io::{self, Read, Write};
+
+use lua_shared as lua;
+use lua_shared::lua_State;
+
+use crate::check_slice;
+
+// Compressed data starts with this byte followed by the algorithm tag.
+const MAGIC: u8 = 0xC5;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Algorithm {
+    Lz4 = 1,
+    Zstd = 2,
+    Deflate = 3,
+}
+
+impl Algorithm {
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            1 => Some(Self::Lz4),
+            2 => Some(Self::Zstd),
+            3 => Some(Self::Deflate),
+            _ => None,
+        }
+    }
+}
+
+/// Reads algorithm name at `index`.
+pub unsafe fn check_algorithm(state: lua_State, index: i32) -> Algorithm {
+    match check_slice!(state, index) {
+        b"lz4" => Algorithm::Lz4,
+        b"zstd" => Algorithm::Zstd,
+        b"deflate" => Algorithm::Deflate,
+        _ => lua::Largerror(
+            state,
+            index,
+            lua::cstr!("expected \"lz4\", \"zstd\" or \"deflate\""),
+        ),
+    }
+}
+
+/// Reads optional compression level at `index`. LZ4 ignores it.
+pub unsafe fn opt_level(state: lua_State, index: i32) -> Option<i32> {
+    if lua::get_type(state, index) > 0 {
+        Some(lua::Lcheckinteger(state, index) as _)
+    } else {
+        None
+    }
+}
+
+pub fn compress(data: &[u8], algorithm: Algorithm, level: Option<i32>) -> io::Result<Vec<u8>> {
+    let mut out = vec![MAGIC, algorithm as u8];
+    match algorithm {
+        Algorithm::Lz4 => out.extend(lz4_flex::compress_prepend_size(data)),
+        Algorithm::Zstd => zstd::stream::copy_encode(data, &mut out, level.unwrap_or(3))?,
+        Algorithm::Deflate => {
+            let level = flate2::Compression::new(level.unwrap_or(6).clamp(0, 9) as u32);
+            let mut encoder = flate2::write::DeflateEncoder::new(out, level);
+            encoder.write_all(data)?;
+            out = encoder.finish()?;
+        }
+    }
+    Ok(out)
+}
+
+/// Whether `data` starts with the header written by `compress`.
+pub fn is_compressed(data: &[u8]) -> bool {
+    data.len() >= 2 && data[0] == MAGIC && Algorithm::from_tag(data[1]).is_some()
+}
+
+/// Values decompressing to more than this are rejected rather than allocated.
+pub const MAX_DECOMPRESSED_SIZE: usize = 64 << 20;
+
+pub fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+    decompress_with_limit(data, MAX_DECOMPRESSED_SIZE)
+}
+
+fn decompress_with_limit(data: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+    if !is_compressed(data) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "data is not compressed",
+        ));
+    }
+    let too_large = || io::Error::new(io::ErrorKind::InvalidData, "decompressed data is too large");
+    let payload = &data[2..];
+    let mut out = Vec::new();
+    // LZ4 size prefix is checked before anything is allocated,
+    // other decoders are capped while reading.
+    let reader: Box<dyn Read + '_> = match Algorithm::from_tag(data[1]) {
+        Some(Algorithm::Lz4) => {
+            if payload.len() < 4 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "data string too short",
+                ));
+            }
+            let size = u32::from_le_bytes(payload[..4].try_into().unwrap()) as usize;
+            if size > limit {
+                return Err(too_large());
+            }
+            return lz4_flex::decompress_size_prepended(payload)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+        Some(Algorithm::Zstd) => Box::new(zstd::stream::read::Decoder::new(payload)?),
+        _ => Box::new(flate2::read::DeflateDecoder::new(payload)),
+    };
+    reader.take(limit as u64 + 1).read_to_end(&mut out)?;
+    if out.len() > limit {
+        return Err(too_large());
+    }
+    Ok(out)
+}
+
+/// `sled.Compress(data, algo[, level])`
+pub fn l_compress(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+    unsafe {
+        let data = check_slice!(state, 1);
+        let data = compress(data, check_algorithm(state, 2), opt_level(state, 3))?;
+        lua::pushlstring(state, data.as_ptr(), data.len());
+        Ok(1)
+    }
+}
+
+/// `sled.Decompress(data)`
+pub fn l_decompress(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+    unsafe {
+        let data = decompress(check_slice!(state, 1))?;
+        lua::pushlstring(state, data.as_ptr(), data.len());
+        Ok(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALGORITHMS: [Algorithm; 3] = [Algorithm::Lz4, Algorithm::Zstd, Algorithm::Deflate];
+
+    #[test]
+    fn roundtrip() {
+        let data = b"hello hello hello hello hello hello".repeat(10);
+        for algorithm in ALGORITHMS {
+            for level in [None, Some(1), Some(9)] {
+                let compressed = compress(&data, algorithm, level).unwrap();
+                assert_eq!(&compressed[..2], &[MAGIC, algorithm as u8]);
+                assert!(is_compressed(&compressed));
+                assert_eq!(decompress(&compressed).unwrap(), data);
+            }
+            let empty = compress(b"", algorithm, None).unwrap();
+            assert_eq!(decompress(&empty).unwrap(), b"");
+        }
+    }
+
+    #[test]
+    fn rejects_uncompressed() {
+        assert!(!is_compressed(b""));
+        assert!(!is_compressed(&[MAGIC]));
+        assert!(!is_compressed(&[MAGIC, 0]));
+        assert!(!is_compressed(&[MAGIC, 4]));
+        assert!(decompress(b"plain").is_err());
+    }
+
+    #[test]
+    fn rejects_oversized_output() {
+        // LZ4 with 4 GiB size prefix and no payload.
+        assert!(decompress(&[MAGIC, 1, 0xFF, 0xFF, 0xFF, 0xFF]).is_err());
+        assert!(decompress(&[MAGIC, 1, 0xFF]).is_err());
+        let data = vec![0; 4096];
+        for algorithm in ALGORITHMS {
+            let compressed = compress(&data, algorithm, None).unwrap();
+            assert!(decompress_with_limit(&compressed, 4095).is_err());
+            assert_eq!(decompress_with_limit(&compressed, 4096).unwrap(), data);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::fmt;
+use std::io;
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+
+// Encrypted data is this byte, random nonce, then ciphertext with the tag.
+const MAGIC: u8 = 0xE5;
+const NONCE_SIZE: usize = 12;
+const KEY_CONTEXT: &str = "lsled 2022-07 value encryption key";
+const CHECK_MESSAGE: &[u8] = b"lsled key check";
+
+/// ChaCha20-Poly1305 keyed from an arbitrary secret string,
+/// and a keyed hash that tells whether another secret gives the same key.
+#[derive(Clone)]
+pub struct Cipher(ChaCha20Poly1305, [u8; 32]);
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Cipher")
+    }
+}
+
+fn invalid_data(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl Cipher {
+    pub fn new(secret: &[u8]) -> Self {
+        let key = blake3::derive_key(KEY_CONTEXT, secret);
+        let check = blake3::keyed_hash(&key, CHECK_MESSAGE);
+        Self(ChaCha20Poly1305::new(&key.into()), *check.as_bytes())
+    }
+
+    /// Safe to store, doesn't reveal the key.
+    pub fn key_check(&self) -> &[u8; 32] {
+        &self.1
+    }
+
+    /// `aad` is authenticated but not stored, same `aad` must be given to `decrypt`.
+    pub fn encrypt(&self, data: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .0
+            .encrypt(&nonce, Payload { msg: data, aad })
+            .map_err(|_| invalid_data("encryption failed"))?;
+        let mut out = Vec::with_capacity(1 + NONCE_SIZE + ciphertext.len());
+        out.push(MAGIC);
+        out.extend_from_slice(&nonce);
+        out.extend(ciphertext);
+        Ok(out)
+    }
+
+    /// Fails if data was tampered with, encrypted with another key or `aad`.
+    pub fn decrypt(&self, data: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+        if !is_encrypted(data) {
+            return Err(invalid_data("data is not encrypted"));
+        }
+        let (nonce, ciphertext) = data[1..].split_at(NONCE_SIZE);
+        let payload = Payload {
+            msg: ciphertext,
+            aad,
+        };
+        self.0
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| invalid_data("decryption failed"))
+    }
+}
+
+// Whether `data` looks like output of `Cipher::encrypt`.
+fn is_encrypted(data: &[u8]) -> bool {
+    // Poly1305 tag is 16 bytes.
+    data.len() >= 1 + NONCE_SIZE + 16 && data[0] == MAGIC
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let cipher = Cipher::new(b"secret");
+        let data = cipher.encrypt(b"hello", b"aad").unwrap();
+        assert_eq!(data.len(), 1 + NONCE_SIZE + 5 + 16);
+        assert_eq!(cipher.decrypt(&data, b"aad").unwrap(), b"hello");
+        assert_ne!(cipher.encrypt(b"hello", b"aad").unwrap(), data);
+    }
+
+    #[test]
+    fn rejects_wrong_key_aad_and_tampering() {
+        let cipher = Cipher::new(b"secret");
+        let mut data = cipher.encrypt(b"hello", b"aad").unwrap();
+        assert!(Cipher::new(b"other").decrypt(&data, b"aad").is_err());
+        assert!(cipher.decrypt(&data, b"other").is_err());
+        assert!(cipher.decrypt(&data[..data.len() - 1], b"aad").is_err());
+        *data.last_mut().unwrap() ^= 1;
+        assert!(cipher.decrypt(&data, b"aad").is_err());
+        assert!(cipher.decrypt(b"hello", b"aad").is_err());
+    }
+
+    #[test]
+    fn key_check() {
+        let cipher = Cipher::new(b"secret");
+        assert_eq!(cipher.key_check(), Cipher::new(b"secret").key_check());
+        assert_ne!(cipher.key_check(), Cipher::new(b"other").key_check());
+    }
+}
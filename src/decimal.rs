@@ -0,0 +1,428 @@
+use std::fmt;
+
+use lua_shared as lua;
+use lua_shared::lua_State;
+
+use crate::insert_function;
+
+pub const MAX_SCALE: u8 = 18;
+
+/// Fixed-point decimal, `value` scaled by 10^`scale`.
+/// Exposed to lua as `sled.Decimal(value[, scale])`.
+#[derive(Debug, Clone, Copy)]
+pub struct Decimal {
+    value: i64,
+    scale: u8,
+}
+
+fn pow10(exp: u8) -> i128 {
+    10i128.pow(exp as u32)
+}
+
+// Rounds half away from zero.
+fn div_round(lhs: i128, rhs: i128) -> i128 {
+    let (quot, rem) = (lhs / rhs, lhs % rhs);
+    if rem.abs() * 2 >= rhs.abs() {
+        quot + if (lhs < 0) == (rhs < 0) { 1 } else { -1 }
+    } else {
+        quot
+    }
+}
+
+impl Decimal {
+    pub fn new(value: i64, scale: u8) -> Self {
+        Self { value, scale }
+    }
+
+    pub fn value(&self) -> i64 {
+        self.value
+    }
+
+    fn from_i128(value: i128, scale: u8) -> Option<Self> {
+        Some(Self::new(i64::try_from(value).ok()?, scale))
+    }
+
+    /// Parses `-123.45`-like strings. Scale is amount of fractional digits
+    /// unless `scale` is given, in which case string can't have more of them.
+    pub fn parse(data: &[u8], scale: Option<u8>) -> Option<Self> {
+        let data = std::str::from_utf8(data).ok()?.trim();
+        let (negative, data) = match data.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, data.strip_prefix('+').unwrap_or(data)),
+        };
+        let (int, frac) = data.split_once('.').unwrap_or((data, ""));
+        if (int.is_empty() && frac.is_empty())
+            || !int
+                .bytes()
+                .chain(frac.bytes())
+                .all(|byte| byte.is_ascii_digit())
+        {
+            return None;
+        }
+        let scale = scale.unwrap_or(frac.len().try_into().ok()?);
+        if frac.len() > scale as usize || scale > MAX_SCALE {
+            return None;
+        }
+        let mut value = 0i128;
+        for byte in int.bytes().chain(frac.bytes()) {
+            value = value.checked_mul(10)? + (byte - b'0') as i128;
+            if value > i64::MAX as i128 + 1 {
+                return None;
+            }
+        }
+        value *= pow10(scale - frac.len() as u8);
+        Self::from_i128(if negative { -value } else { value }, scale)
+    }
+
+    /// Converts number through its shortest exact representation, so `0.1` becomes `0.1`.
+    pub fn from_number(value: f64, scale: Option<u8>) -> Option<Self> {
+        if !value.is_finite() {
+            return None;
+        }
+        match scale {
+            Some(scale) if scale <= MAX_SCALE => {
+                Self::from_i128((value * pow10(scale) as f64).round() as i128, scale)
+            }
+            Some(_) => None,
+            None => Self::parse(value.to_string().as_bytes(), None),
+        }
+    }
+
+    pub fn to_number(&self) -> f64 {
+        self.value as f64 / pow10(self.scale) as f64
+    }
+
+    /// Changes scale, rounding half away from zero if it gets lower.
+    pub fn rescale(&self, scale: u8) -> Option<Self> {
+        if scale > MAX_SCALE {
+            return None;
+        }
+        let value = self.value as i128;
+        if scale >= self.scale {
+            Self::from_i128(value * pow10(scale - self.scale), scale)
+        } else {
+            Self::from_i128(div_round(value, pow10(self.scale - scale)), scale)
+        }
+    }
+
+    /// Changes scale only if no digits are lost.
+    pub fn with_scale(&self, scale: u8) -> Option<Self> {
+        let result = self.rescale(scale)?;
+        match result.rescale(self.scale) {
+            Some(back) if back.value == self.value => Some(result),
+            _ => None,
+        }
+    }
+
+    fn align(&self, other: &Self) -> Option<(i128, i128, u8)> {
+        let scale = self.scale.max(other.scale);
+        Some((
+            self.rescale(scale)?.value as i128,
+            other.rescale(scale)?.value as i128,
+            scale,
+        ))
+    }
+
+    pub fn checked_add(&self, other: &Self) -> Option<Self> {
+        let (lhs, rhs, scale) = self.align(other)?;
+        Self::from_i128(lhs + rhs, scale)
+    }
+
+    pub fn checked_sub(&self, other: &Self) -> Option<Self> {
+        let (lhs, rhs, scale) = self.align(other)?;
+        Self::from_i128(lhs - rhs, scale)
+    }
+
+    pub fn checked_mul(&self, other: &Self) -> Option<Self> {
+        let scale = self.scale.max(other.scale);
+        let value = (self.value as i128).checked_mul(other.value as i128)?;
+        Self::from_i128(
+            div_round(value, pow10(self.scale + other.scale - scale)),
+            scale,
+        )
+    }
+
+    pub fn checked_div(&self, other: &Self) -> Option<Self> {
+        if other.value == 0 {
+            return None;
+        }
+        let scale = self.scale.max(other.scale);
+        let value = (self.value as i128).checked_mul(pow10(scale + other.scale - self.scale))?;
+        Self::from_i128(div_round(value, other.value as i128), scale)
+    }
+
+    fn compare(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        let (lhs, rhs, _) = self.align(other)?;
+        Some(lhs.cmp(&rhs))
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.value < 0 { "-" } else { "" };
+        let value = self.value.unsigned_abs() as u128;
+        let scale = pow10(self.scale) as u128;
+        if self.scale == 0 {
+            write!(f, "{}{}", sign, value)
+        } else {
+            write!(
+                f,
+                "{}{}.{:0width$}",
+                sign,
+                value / scale,
+                value % scale,
+                width = self.scale as usize
+            )
+        }
+    }
+}
+
+/// Accepts `sled.Decimal`, decimal string or number at `index`.
+pub unsafe fn to_decimal(state: lua_State, index: i32) -> Option<Decimal> {
+    match lua::get_type(state, index) {
+        3 => Decimal::from_number(lua::tonumber(state, index), None),
+        4 => {
+            let mut len = 0;
+            let data = lua::tolstring(state, index, &mut len);
+            Decimal::parse(std::slice::from_raw_parts(data, len), None)
+        }
+        7 => Some(*lua::Lcheckudata(state, index, lua::cstr!("csldec")).cast::<Decimal>()),
+        _ => None,
+    }
+}
+
+pub unsafe fn check_decimal(state: lua_State, index: i32) -> Decimal {
+    match to_decimal(state, index) {
+        Some(decimal) => decimal,
+        None => lua::Largerror(state, index, lua::cstr!("decimal expected")),
+    }
+}
+
+pub unsafe fn push_decimal(state: lua_State, decimal: Decimal) {
+    let udata = lua::newuserdata(state, std::mem::size_of::<Decimal>()).cast::<Decimal>();
+    udata.write(decimal);
+    Decimal::metatable(state);
+    lua::setmetatable(state, -2);
+}
+
+macro_rules! def_arith {
+    ($name:ident, $method:ident) => {
+        fn $name(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+            unsafe {
+                let lhs = check_decimal(state, 1);
+                let rhs = check_decimal(state, 2);
+                match lhs.$method(&rhs) {
+                    Some(result) => push_decimal(state, result),
+                    None => return Err("decimal overflow or division by zero".into()),
+                }
+                Ok(1)
+            }
+        }
+    };
+}
+
+impl Decimal {
+    pub fn l_new(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let scale = if lua::get_type(state, 2) > 0 {
+                Some(lua::Lcheckinteger(state, 2).clamp(0, u8::MAX as _) as u8)
+            } else {
+                None
+            };
+            let decimal = match lua::get_type(state, 1) {
+                3 => Decimal::from_number(lua::tonumber(state, 1), scale),
+                4 => Decimal::parse(crate::check_slice!(state, 1), scale),
+                _ => match scale {
+                    Some(scale) => check_decimal(state, 1).rescale(scale),
+                    None => Some(check_decimal(state, 1)),
+                },
+            };
+            match decimal {
+                Some(decimal) => push_decimal(state, decimal),
+                None => lua::Largerror(state, 1, lua::cstr!("invalid decimal")),
+            }
+            Ok(1)
+        }
+    }
+
+    def_arith!(__add, checked_add);
+    def_arith!(__sub, checked_sub);
+    def_arith!(__mul, checked_mul);
+    def_arith!(__div, checked_div);
+
+    fn __unm(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = check_decimal(state, 1);
+            match this.value.checked_neg() {
+                Some(value) => push_decimal(state, Decimal::new(value, this.scale)),
+                None => return Err("decimal overflow".into()),
+            }
+            Ok(1)
+        }
+    }
+
+    fn __eq(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let ordering = check_decimal(state, 1).compare(&check_decimal(state, 2));
+            lua::pushboolean(state, (ordering == Some(std::cmp::Ordering::Equal)) as _);
+            Ok(1)
+        }
+    }
+
+    fn __lt(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let ordering = check_decimal(state, 1).compare(&check_decimal(state, 2));
+            lua::pushboolean(state, (ordering == Some(std::cmp::Ordering::Less)) as _);
+            Ok(1)
+        }
+    }
+
+    fn __le(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let ordering = check_decimal(state, 1).compare(&check_decimal(state, 2));
+            lua::pushboolean(
+                state,
+                matches!(
+                    ordering,
+                    Some(std::cmp::Ordering::Less | std::cmp::Ordering::Equal)
+                ) as _,
+            );
+            Ok(1)
+        }
+    }
+
+    fn __tostring(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = *lua::Lcheckudata(state, 1, lua::cstr!("csldec")).cast::<Self>();
+            let str = this.to_string();
+            lua::pushlstring(state, str.as_ptr(), str.len());
+            Ok(1)
+        }
+    }
+
+    fn lm_to_number(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = *lua::Lcheckudata(state, 1, lua::cstr!("csldec")).cast::<Self>();
+            lua::pushnumber(state, this.to_number());
+            Ok(1)
+        }
+    }
+
+    fn lm_scale(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = *lua::Lcheckudata(state, 1, lua::cstr!("csldec")).cast::<Self>();
+            lua::pushinteger(state, this.scale as _);
+            Ok(1)
+        }
+    }
+
+    fn lm_rescale(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = *lua::Lcheckudata(state, 1, lua::cstr!("csldec")).cast::<Self>();
+            let scale = lua::Lcheckinteger(state, 2).clamp(0, u8::MAX as _) as u8;
+            match this.rescale(scale) {
+                Some(decimal) => push_decimal(state, decimal),
+                None => return Err("decimal overflow".into()),
+            }
+            Ok(1)
+        }
+    }
+
+    pub unsafe fn metatable(state: lua_State) {
+        if lua::Lnewmetatable(state, lua::cstr!("csldec")) {
+            lua::pushvalue(state, -1);
+            lua::setfield(state, -2, lua::cstr!("__index"));
+            insert_function!(state, "__add", Self::__add);
+            insert_function!(state, "__sub", Self::__sub);
+            insert_function!(state, "__mul", Self::__mul);
+            insert_function!(state, "__div", Self::__div);
+            insert_function!(state, "__unm", Self::__unm);
+            insert_function!(state, "__eq", Self::__eq);
+            insert_function!(state, "__lt", Self::__lt);
+            insert_function!(state, "__le", Self::__le);
+            insert_function!(state, "__tostring", Self::__tostring);
+            insert_function!(state, "ToString", Self::__tostring);
+            insert_function!(state, "ToNumber", Self::lm_to_number);
+            insert_function!(state, "Scale", Self::lm_scale);
+            insert_function!(state, "Rescale", Self::lm_rescale);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(data: &str) -> Decimal {
+        Decimal::parse(data.as_bytes(), None).unwrap()
+    }
+
+    #[test]
+    fn parse_and_display() {
+        let cases = [
+            ("-123.45", "-123.45"),
+            ("+.5", "0.5"),
+            ("7.", "7"),
+            (" 0.010 ", "0.010"),
+            ("-9223372036854775808", "-9223372036854775808"),
+        ];
+        for (input, output) in cases {
+            assert_eq!(parse(input).to_string(), output);
+        }
+        assert_eq!(
+            Decimal::parse(b"1.5", Some(3)).unwrap().to_string(),
+            "1.500"
+        );
+        for input in ["", ".", "-", "1.2.3", "1e5", "9223372036854775808"] {
+            assert!(
+                Decimal::parse(input.as_bytes(), None).is_none(),
+                "{}",
+                input
+            );
+        }
+        assert!(Decimal::parse(b"1.234", Some(2)).is_none());
+        assert!(Decimal::parse(b"1", Some(MAX_SCALE + 1)).is_none());
+    }
+
+    #[test]
+    fn from_number() {
+        assert_eq!(Decimal::from_number(0.1, None).unwrap().to_string(), "0.1");
+        assert_eq!(
+            Decimal::from_number(1.25, Some(1)).unwrap().to_string(),
+            "1.3"
+        );
+        assert!(Decimal::from_number(f64::NAN, None).is_none());
+        assert!(Decimal::from_number(1.0, Some(MAX_SCALE + 1)).is_none());
+    }
+
+    #[test]
+    fn rescale_rounds_half_away_from_zero() {
+        assert_eq!(Decimal::new(125, 2).rescale(1).unwrap().value(), 13);
+        assert_eq!(Decimal::new(-125, 2).rescale(1).unwrap().value(), -13);
+        assert_eq!(Decimal::new(124, 2).rescale(1).unwrap().value(), 12);
+        assert_eq!(Decimal::new(15, 1).rescale(3).unwrap().value(), 1500);
+        assert!(Decimal::new(i64::MAX, 0).rescale(1).is_none());
+        assert_eq!(Decimal::new(150, 2).with_scale(1).unwrap().value(), 15);
+        assert!(Decimal::new(155, 2).with_scale(1).is_none());
+    }
+
+    #[test]
+    fn arithmetic() {
+        let (a, b) = (parse("1.5"), parse("0.25"));
+        assert_eq!(a.checked_add(&b).unwrap().to_string(), "1.75");
+        assert_eq!(a.checked_sub(&b).unwrap().to_string(), "1.25");
+        assert_eq!(a.checked_mul(&b).unwrap().to_string(), "0.38");
+        assert_eq!(
+            parse("2.00").checked_div(&parse("3")).unwrap().to_string(),
+            "0.67"
+        );
+        assert!(a.checked_div(&parse("0.0")).is_none());
+        assert!(Decimal::new(i64::MAX, 0)
+            .checked_add(&Decimal::new(1, 0))
+            .is_none());
+        assert_eq!(
+            parse("1.0").compare(&parse("1.00")),
+            Some(std::cmp::Ordering::Equal)
+        );
+    }
+}
@@ -0,0 +1,81 @@
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use lua_shared as lua;
+use lua_shared::lua_State;
+
+use crate::check_slice;
+
+pub fn to_base64(data: &[u8], url_safe: bool) -> String {
+    if url_safe {
+        URL_SAFE_NO_PAD.encode(data)
+    } else {
+        STANDARD.encode(data)
+    }
+}
+
+/// URL-safe input may come with or without padding.
+pub fn from_base64(data: &[u8], url_safe: bool) -> Result<Vec<u8>, base64::DecodeError> {
+    if url_safe {
+        let len = data
+            .iter()
+            .rposition(|byte| *byte != b'=')
+            .map_or(0, |i| i + 1);
+        URL_SAFE_NO_PAD.decode(&data[..len])
+    } else {
+        STANDARD.decode(data)
+    }
+}
+
+/// `sled.ToHex(data)`
+pub fn l_to_hex(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+    unsafe {
+        let str = hex::encode(check_slice!(state, 1));
+        lua::pushlstring(state, str.as_ptr(), str.len());
+        Ok(1)
+    }
+}
+
+/// `sled.FromHex(str)`
+pub fn l_from_hex(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+    unsafe {
+        let data = hex::decode(check_slice!(state, 1))?;
+        lua::pushlstring(state, data.as_ptr(), data.len());
+        Ok(1)
+    }
+}
+
+/// `sled.ToBase64(data[, urlsafe])`
+pub fn l_to_base64(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+    unsafe {
+        let str = to_base64(check_slice!(state, 1), lua::toboolean(state, 2) as u8 != 0);
+        lua::pushlstring(state, str.as_ptr(), str.len());
+        Ok(1)
+    }
+}
+
+/// `sled.FromBase64(str[, urlsafe])`
+pub fn l_from_base64(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+    unsafe {
+        let data = from_base64(check_slice!(state, 1), lua::toboolean(state, 2) as u8 != 0)?;
+        lua::pushlstring(state, data.as_ptr(), data.len());
+        Ok(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64() {
+        assert_eq!(to_base64(b"\xFB\xFF", false), "+/8=");
+        assert_eq!(to_base64(b"\xFB\xFF", true), "-_8");
+        assert_eq!(to_base64(b"", false), "");
+        assert_eq!(from_base64(b"+/8=", false).unwrap(), b"\xFB\xFF");
+        assert_eq!(from_base64(b"-_8", true).unwrap(), b"\xFB\xFF");
+        assert_eq!(from_base64(b"-_8=", true).unwrap(), b"\xFB\xFF");
+        assert!(from_base64(b"+/8=", true).is_err());
+        assert!(from_base64(b"-_8=", false).is_err());
+        assert!(from_base64(b"+/8", false).is_err());
+    }
+}
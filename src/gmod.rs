@@ -0,0 +1,77 @@
+use lua_shared as lua;
+use lua_shared::lua_State;
+
+use crate::test_metatable;
+
+unsafe fn get_number(state: lua_State, index: i32, field: *const u8) -> f64 {
+    lua::getfield(state, index, field);
+    let value = lua::tonumber(state, -1);
+    lua::settop(state, -2);
+    value
+}
+
+unsafe fn call_global(state: lua_State, name: *const u8, args: &[f64]) {
+    lua::getfield(state, lua::GLOBALSINDEX, name);
+    for arg in args {
+        lua::pushnumber(state, *arg);
+    }
+    lua::call(state, args.len() as _, 1);
+}
+
+pub unsafe fn is_vector(state: lua_State, index: i32) -> bool {
+    test_metatable(state, index, lua::cstr!("Vector"))
+}
+
+pub unsafe fn is_angle(state: lua_State, index: i32) -> bool {
+    test_metatable(state, index, lua::cstr!("Angle"))
+}
+
+/// Reads components of `Vector` at absolute stack `index`.
+pub unsafe fn check_vector(state: lua_State, index: i32) -> [f64; 3] {
+    lua::Lcheckudata(state, index, lua::cstr!("Vector"));
+    [
+        get_number(state, index, lua::cstr!("x")),
+        get_number(state, index, lua::cstr!("y")),
+        get_number(state, index, lua::cstr!("z")),
+    ]
+}
+
+/// Reads components of `Angle` at absolute stack `index`.
+pub unsafe fn check_angle(state: lua_State, index: i32) -> [f64; 3] {
+    lua::Lcheckudata(state, index, lua::cstr!("Angle"));
+    [
+        get_number(state, index, lua::cstr!("p")),
+        get_number(state, index, lua::cstr!("y")),
+        get_number(state, index, lua::cstr!("r")),
+    ]
+}
+
+/// Reads components of `Color` table at absolute stack `index`.
+/// Missing alpha is treated as 255, same as `Color` does.
+pub unsafe fn check_color(state: lua_State, index: i32) -> [u8; 4] {
+    lua::Lchecktype(state, index, 5);
+    lua::getfield(state, index, lua::cstr!("a"));
+    let alpha = match lua::get_type(state, -1) {
+        3 => lua::tonumber(state, -1) as u8,
+        _ => 255,
+    };
+    lua::settop(state, -2);
+    [
+        get_number(state, index, lua::cstr!("r")) as u8,
+        get_number(state, index, lua::cstr!("g")) as u8,
+        get_number(state, index, lua::cstr!("b")) as u8,
+        alpha,
+    ]
+}
+
+pub unsafe fn push_vector(state: lua_State, value: [f64; 3]) {
+    call_global(state, lua::cstr!("Vector"), &value);
+}
+
+pub unsafe fn push_angle(state: lua_State, value: [f64; 3]) {
+    call_global(state, lua::cstr!("Angle"), &value);
+}
+
+pub unsafe fn push_color(state: lua_State, value: [u8; 4]) {
+    call_global(state, lua::cstr!("Color"), &value.map(|c| c as f64));
+}
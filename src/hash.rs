@@ -0,0 +1,65 @@
+use lua_shared as lua;
+use lua_shared::lua_State;
+use sha1::Digest;
+
+use crate::check_slice;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Algorithm {
+    Crc32,
+    Xxh64,
+    Xxh3,
+    Sha1,
+    Sha256,
+    Blake3,
+}
+
+pub unsafe fn check_algorithm(state: lua_State, index: i32) -> Algorithm {
+    match check_slice!(state, index) {
+        b"crc32" => Algorithm::Crc32,
+        b"xxh64" => Algorithm::Xxh64,
+        b"xxh3" => Algorithm::Xxh3,
+        b"sha1" => Algorithm::Sha1,
+        b"sha256" => Algorithm::Sha256,
+        b"blake3" => Algorithm::Blake3,
+        _ => lua::Largerror(
+            state,
+            index,
+            lua::cstr!(
+                "expected \"crc32\", \"xxh64\", \"xxh3\", \"sha1\", \"sha256\" or \"blake3\""
+            ),
+        ),
+    }
+}
+
+/// Integer hashes are returned big-endian, so hex output matches the usual notation.
+pub fn digest(algorithm: Algorithm, data: &[u8]) -> Vec<u8> {
+    match algorithm {
+        Algorithm::Crc32 => crc32fast::hash(data).to_be_bytes().to_vec(),
+        Algorithm::Xxh64 => xxhash_rust::xxh64::xxh64(data, 0).to_be_bytes().to_vec(),
+        Algorithm::Xxh3 => xxhash_rust::xxh3::xxh3_64(data).to_be_bytes().to_vec(),
+        Algorithm::Sha1 => sha1::Sha1::digest(data).to_vec(),
+        Algorithm::Sha256 => sha2::Sha256::digest(data).to_vec(),
+        Algorithm::Blake3 => blake3::hash(data).as_bytes().to_vec(),
+    }
+}
+
+/// Pushes `digest` as lowercase hex, or as is if `raw` is set.
+pub unsafe fn push_digest(state: lua_State, digest: &[u8], raw: bool) {
+    if raw {
+        lua::pushlstring(state, digest.as_ptr(), digest.len());
+    } else {
+        let digest = hex::encode(digest);
+        lua::pushlstring(state, digest.as_ptr(), digest.len());
+    }
+}
+
+/// `sled.Hash(algo, data[, raw])`
+pub fn l_hash(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+    unsafe {
+        let algorithm = check_algorithm(state, 1);
+        let digest = digest(algorithm, check_slice!(state, 2));
+        push_digest(state, &digest, lua::toboolean(state, 3) as u8 != 0);
+        Ok(1)
+    }
+}
@@ -4,12 +4,19 @@ use std::ptr::null;
 use lua_shared as lua;
 use lua_shared::lua_State;
 
+use crate::buffer::{self, Buffer};
+use crate::codec::{self, Codecs, SharedCodec};
+use crate::compress;
+use crate::crypto::Cipher;
 use crate::ltree::LTree;
-use crate::lua_struct::StructError;
-use crate::{check_slice, insert_function, lua_struct, tree_get_key, tree_get_no_arg};
+use crate::schema::Schema;
+use crate::{
+    check_slice, insert_function, lua_struct, struct_try, tree_get_key, tree_get_no_arg, tree_pop,
+};
 
 #[derive(Debug, Clone)]
-pub struct LDb(pub sled::Db);
+/// Database, codec of its default tree and codecs of trees opened from it.
+pub struct LDb(pub sled::Db, pub SharedCodec, pub Codecs);
 impl Deref for LDb {
     type Target = sled::Db;
     fn deref(&self) -> &Self::Target {
@@ -24,11 +31,17 @@ impl DerefMut for LDb {
 }
 
 impl LDb {
+    fn decode_value(&self, key: &[u8], value: sled::IVec) -> std::io::Result<sled::IVec> {
+        self.1.get().decode(key, value)
+    }
+
     pub fn l_open(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
         unsafe {
             let db = sled::open(std::str::from_utf8_unchecked(check_slice!(state, 1)))?;
+            let codecs = Codecs::default();
+            let codec = codecs.get(&db, db.name())?;
             let ldb = lua::newuserdata(state, std::mem::size_of::<Self>()).cast::<Self>();
-            ldb.write(Self(db));
+            ldb.write(Self(db, codec, codecs));
             Self::metatable(state);
             lua::setmetatable(state, -2);
         }
@@ -55,7 +68,9 @@ impl LDb {
     fn lm_get(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
         unsafe {
             let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("csldb")).cast::<Self>();
-            if let Some(ivec) = this.0.get(check_slice!(state, 2))? {
+            let key = check_slice!(state, 2);
+            if let Some(ivec) = this.0.get(key)? {
+                let ivec = this.decode_value(key, ivec)?;
                 lua::pushlstring(state, ivec.as_ptr(), ivec.len());
                 Ok(1)
             } else {
@@ -68,30 +83,28 @@ impl LDb {
         unsafe {
             let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("csldb")).cast::<Self>();
             let key = check_slice!(state, 2);
-            let fmt = check_slice!(state, 3);
-            if let Some(ivec) = this.0.get(key)? {
-                match lua_struct::unpack(state, fmt, &ivec) {
-                    Ok(args) => Ok(args),
-                    Err(e) => {
-                        drop(ivec);
-                        match e {
-                            StructError::Error(e) => lua::Lerror(state, e),
-                            StructError::ArgError(arg, e) => lua::Largerror(state, arg, e),
-                            StructError::InvalidFormatOption(e, opt) => lua::Lerror(state, e, opt),
-                            StructError::IOError(e) => return Err(e)?,
-                        }
-                    }
-                }
-            } else {
-                Ok(0)
+            if let Some(schema) = Schema::test(state, 3) {
+                return match schema.get(state, &this.0, &this.1.get(), key, false)? {
+                    Some(value) => schema.unpack_values(state, &value),
+                    None => Ok(0),
+                };
             }
+            let fmt = struct_try!(state, lua_struct::check_struct(state, 3));
+            let result = if let Some(ivec) = this.0.get(key)? {
+                lua_struct::unpack(state, &fmt, &this.decode_value(key, ivec)?)
+            } else {
+                return Ok(0);
+            };
+            Ok(struct_try!(state, result))
         }
     }
 
     fn lm_insert(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
         unsafe {
             let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("csldb")).cast::<Self>();
-            this.insert(check_slice!(state, 2), check_slice!(state, 3))?;
+            let key = check_slice!(state, 2);
+            let value = this.1.get().encode(key, buffer::check_bytes(state, 3))?;
+            this.insert(key, value.as_ref())?;
             Ok(0)
         }
     }
@@ -100,15 +113,64 @@ impl LDb {
         unsafe {
             let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("csldb")).cast::<Self>();
             let key = check_slice!(state, 2);
-            let fmt = check_slice!(state, 3);
-            let value = match lua_struct::pack(state, fmt, 4) {
-                Ok(result) => result,
-                Err(StructError::Error(e)) => lua::Lerror(state, e),
-                Err(StructError::ArgError(arg, e)) => lua::Largerror(state, arg, e),
-                Err(StructError::InvalidFormatOption(e, opt)) => lua::Lerror(state, e, opt),
-                Err(StructError::IOError(e)) => return Err(e)?,
+            let fmt = struct_try!(state, lua_struct::check_struct(state, 3));
+            let value = match Buffer::test(state, 4) {
+                Some(buffer) => buffer::check_struct_data(&fmt, buffer)?,
+                None => struct_try!(state, lua_struct::pack(state, &fmt, 4)),
             };
-            this.insert(key, value)?;
+            this.insert(key, this.1.get().encode(key, value)?.as_ref())?;
+            Ok(0)
+        }
+    }
+
+    fn lm_get_buffer(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("csldb")).cast::<Self>();
+            let key = check_slice!(state, 2);
+            if let Some(ivec) = this.0.get(key)? {
+                Buffer::fill_or_push(state, 3, &this.decode_value(key, ivec)?);
+                Ok(1)
+            } else {
+                Ok(0)
+            }
+        }
+    }
+
+    fn lm_get_struct_table(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("csldb")).cast::<Self>();
+            let key = check_slice!(state, 2);
+            let fmt = struct_try!(state, lua_struct::check_struct(state, 3));
+            let result = if let Some(ivec) = this.0.get(key)? {
+                lua_struct::unpack_table(state, &fmt, &this.decode_value(key, ivec)?)
+            } else {
+                return Ok(0);
+            };
+            Ok(struct_try!(state, result))
+        }
+    }
+
+    fn lm_get_record(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("csldb")).cast::<Self>();
+            let key = check_slice!(state, 2);
+            let schema = &*lua::Lcheckudata(state, 3, lua::cstr!("cslsc")).cast::<Schema>();
+            let writeback = lua::toboolean(state, 4) as u8 != 0;
+            if let Some(value) = schema.get(state, &this.0, &this.1.get(), key, writeback)? {
+                schema.unpack(state, &value)
+            } else {
+                Ok(0)
+            }
+        }
+    }
+
+    fn lm_insert_record(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("csldb")).cast::<Self>();
+            let key = check_slice!(state, 2);
+            let schema = &*lua::Lcheckudata(state, 3, lua::cstr!("cslsc")).cast::<Schema>();
+            let value = schema.pack(state, 4)?;
+            this.insert(key, this.1.get().encode(key, &value)?.as_ref())?;
             Ok(0)
         }
     }
@@ -125,9 +187,11 @@ impl LDb {
         unsafe {
             let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("csldb")).cast::<Self>();
             let mut range = this.range(check_slice!(state, 2)..=check_slice!(state, 3));
+            let codec = this.1.clone();
             lua::pushfunction(state, move |state| {
                 if let Some(tree_name) = range.next() {
                     let (key, value) = tree_name?;
+                    let value = codec.get().decode(&key, value)?;
                     lua::pushlstring(state, key.as_ptr(), key.len());
                     lua::pushlstring(state, value.as_ptr(), value.len());
                     Ok(2)
@@ -147,9 +211,11 @@ impl LDb {
                 std::slice::from_raw_parts(lua::Loptlstring(state, 2, null(), &mut len), len)
             };
             let mut prefix = this.scan_prefix(prefix);
+            let codec = this.1.clone();
             lua::pushfunction(state, move |state| {
                 if let Some(tree_name) = prefix.next() {
                     let (key, value) = tree_name?;
+                    let value = codec.get().decode(&key, value)?;
                     lua::pushlstring(state, key.as_ptr(), key.len());
                     lua::pushlstring(state, value.as_ptr(), value.len());
                     Ok(2)
@@ -164,7 +230,11 @@ impl LDb {
     fn lm_tree_names(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
         unsafe {
             let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("csldb")).cast::<Self>();
-            let mut iter = this.0.tree_names().into_iter();
+            let mut iter = this
+                .0
+                .tree_names()
+                .into_iter()
+                .filter(|name| !codec::is_reserved(name));
             lua::pushfunction(state, move |state| {
                 if let Some(tree_name) = iter.next() {
                     lua::pushlstring(state, tree_name.as_ptr(), tree_name.len());
@@ -180,9 +250,14 @@ impl LDb {
     fn lm_open_tree(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
         unsafe {
             let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("csldb")).cast::<Self>();
-            let tree = this.open_tree(std::str::from_utf8_unchecked(check_slice!(state, 2)))?;
+            let name = check_slice!(state, 2);
+            if codec::is_reserved(name) {
+                return Err("tree name is reserved".into());
+            }
+            let tree = this.open_tree(std::str::from_utf8_unchecked(name))?;
+            let codec = this.2.get(&this.0, tree.name())?;
             let ltree = lua::newuserdata(state, std::mem::size_of::<LTree>()).cast::<LTree>();
-            ltree.write(LTree(tree));
+            ltree.write(LTree(tree, codec));
             LTree::metatable(state);
             lua::setmetatable(state, -2);
             Ok(1)
@@ -192,10 +267,15 @@ impl LDb {
     fn lm_drop_tree(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
         unsafe {
             let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("csldb")).cast::<Self>();
-            lua::pushboolean(
-                state,
-                this.drop_tree(std::str::from_utf8_unchecked(check_slice!(state, 2)))? as _,
-            );
+            let name = check_slice!(state, 2);
+            if codec::is_reserved(name) {
+                return Err("tree name is reserved".into());
+            }
+            let dropped = this.drop_tree(std::str::from_utf8_unchecked(name))?;
+            if dropped {
+                this.2.drop_tree(&this.0, name)?;
+            }
+            lua::pushboolean(state, dropped as _);
             Ok(1)
         }
     }
@@ -259,6 +339,64 @@ impl LDb {
         }
     }
 
+    fn lm_validate_struct(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("csldb")).cast::<Self>();
+            let fmt = struct_try!(state, lua_struct::check_struct(state, 2));
+            let prefix = {
+                let mut len = 0;
+                std::slice::from_raw_parts(lua::Loptlstring(state, 3, null(), &mut len), len)
+            };
+            lua::createtable(state, 0, 0);
+            let mut invalid = 0;
+            for entry in this.scan_prefix(prefix) {
+                let (key, value) = entry?;
+                let value = this.decode_value(&key, value)?;
+                if let Some(e) = lua_struct::validate(&fmt, &value) {
+                    invalid += 1;
+                    lua::createtable(state, 0, 2);
+                    lua::pushlstring(state, key.as_ptr(), key.len());
+                    lua::setfield(state, -2, lua::cstr!("key"));
+                    lua::pushlstring(state, e.as_ptr(), e.len());
+                    lua::setfield(state, -2, lua::cstr!("error"));
+                    lua::rawseti(state, -2, invalid);
+                }
+            }
+            Ok(1)
+        }
+    }
+
+    /// `SetCompression(algo[, level])`, same as for trees.
+    fn lm_set_compression(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("csldb")).cast::<Self>();
+            let compression = if lua::get_type(state, 2) > 0 {
+                Some((
+                    compress::check_algorithm(state, 2),
+                    compress::opt_level(state, 3),
+                ))
+            } else {
+                None
+            };
+            this.1.set_compression(&this.0, compression)?;
+            Ok(0)
+        }
+    }
+
+    /// `SetEncryption(secret)`, same as for trees.
+    fn lm_set_encryption(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("csldb")).cast::<Self>();
+            let encryption = if lua::get_type(state, 2) > 0 {
+                Some(Cipher::new(check_slice!(state, 2)))
+            } else {
+                None
+            };
+            this.1.set_encryption(&this.0, encryption)?;
+            Ok(0)
+        }
+    }
+
     fn lm_flush(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
         unsafe {
             let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("csldb")).cast::<Self>();
@@ -285,7 +423,8 @@ impl LDb {
     }
 
     tree_get_key!(get_lt get_gt, "csldb");
-    tree_get_no_arg!(first last pop_max pop_min, "csldb");
+    tree_get_no_arg!(first last, "csldb");
+    tree_pop!(pop_max pop_min, "csldb");
 
     fn __gc(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
         unsafe {
@@ -305,11 +444,18 @@ impl LDb {
             insert_function!(state, "Clear", Self::lm_clear);
             insert_function!(state, "Get", Self::lm_get);
             insert_function!(state, "GetStruct", Self::lm_get_struct);
+            insert_function!(state, "GetStructTable", Self::lm_get_struct_table);
+            insert_function!(state, "GetBuffer", Self::lm_get_buffer);
             insert_function!(state, "Insert", Self::lm_insert);
             insert_function!(state, "InsertStruct", Self::lm_insert_struct);
+            insert_function!(state, "GetRecord", Self::lm_get_record);
+            insert_function!(state, "InsertRecord", Self::lm_insert_record);
             insert_function!(state, "Remove", Self::lm_remove);
             insert_function!(state, "Range", Self::lm_range);
             insert_function!(state, "ScanPrefix", Self::lm_scan_prefix);
+            insert_function!(state, "ValidateStruct", Self::lm_validate_struct);
+            insert_function!(state, "SetCompression", Self::lm_set_compression);
+            insert_function!(state, "SetEncryption", Self::lm_set_encryption);
             insert_function!(state, "TreeNames", Self::lm_tree_names);
             insert_function!(state, "OpenTree", Self::lm_open_tree);
             insert_function!(state, "GenerateID", Self::lm_generate_id);
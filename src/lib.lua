@@ -33,3 +33,51 @@ do
         return tree
     end
 end
+
+do
+    local migrations = 0
+
+    -- Upgrades every record in the tree, `batch` records per tick.
+    -- `callback` receives amount of migrated records and list of {key = ..., error = ...}
+    -- for records that failed to migrate, those are left as they were.
+    function CSLT_META:MigrateAll(schema, batch, callback)
+        batch = batch or 256
+        migrations = migrations + 1
+        local name = "sled.MigrateAll." .. migrations
+        local iter = self:ScanPrefix()
+        local version = schema:Version()
+        local migrated, failures = 0, {}
+        timer.Create(name, 0, 0, function()
+            for _ = 1, batch do
+                local key, value = iter()
+                if key == nil then
+                    timer.Remove(name)
+                    if callback then callback(migrated, failures) end
+                    return
+                end
+                if schema:Version(value) ~= version then
+                    local ok, err = pcall(self.GetRecord, self, key, schema, true)
+                    if ok then
+                        migrated = migrated + 1
+                    else
+                        failures[#failures + 1] = {key = key, error = err}
+                    end
+                end
+            end
+        end)
+    end
+end
+
+do
+    -- Secrets live in the handle, so unlocked handles must outlive the weak caches.
+    local pinned = {}
+
+    for _, meta in ipairs({CSLDB_META, CSLT_META}) do
+        local set_encryption = meta.SetEncryption
+
+        function meta:SetEncryption(secret)
+            set_encryption(self, secret)
+            pinned[self] = secret ~= nil or nil
+        end
+    end
+end
@@ -1,19 +1,56 @@
 use buffer::Buffer;
+use decimal::Decimal;
 use ldb::LDb;
 use lua_shared as lua;
 use lua_shared::lua_State;
+use lua_struct::Struct;
+use schema::Schema;
 
 mod buffer;
+mod codec;
+mod compress;
+mod crypto;
+mod decimal;
+mod encoding;
+mod gmod;
+mod hash;
 mod ldb;
 mod ltree;
 mod lua_struct;
 mod macros;
+mod schema;
+mod serialize;
+
+/// Whether value at `index` is a userdata with the metatable registered as `name`.
+pub unsafe fn test_metatable(state: lua_State, index: i32, name: *const u8) -> bool {
+    if lua::get_type(state, index) != 7 || lua::getmetatable(state, index) == 0 {
+        return false;
+    }
+    lua::getfield(state, lua::REGISTRYINDEX, name);
+    let result = lua::rawequal(state, -1, -2) != 0;
+    lua::settop(state, -3);
+    result
+}
 
 #[no_mangle]
 unsafe extern "C" fn gmod13_open(state: lua_State) -> i32 {
     lua::createtable(state, 0, 1);
     insert_function!(state, "Open", LDb::l_open);
     insert_function!(state, "Buffer", Buffer::l_new);
+    insert_function!(state, "Struct", Struct::l_new);
+    insert_function!(state, "UnpackTable", Struct::l_unpack_table);
+    insert_function!(state, "DescribeFormat", Struct::l_describe);
+    insert_function!(state, "Schema", Schema::l_new);
+    insert_function!(state, "Decimal", Decimal::l_new);
+    insert_function!(state, "Compress", compress::l_compress);
+    insert_function!(state, "Decompress", compress::l_decompress);
+    insert_function!(state, "Hash", hash::l_hash);
+    insert_function!(state, "ToHex", encoding::l_to_hex);
+    insert_function!(state, "FromHex", encoding::l_from_hex);
+    insert_function!(state, "ToBase64", encoding::l_to_base64);
+    insert_function!(state, "FromBase64", encoding::l_from_base64);
+    insert_function!(state, "Serialize", serialize::l_serialize);
+    insert_function!(state, "Deserialize", serialize::l_deserialize);
     lua::pushstring(state, lua::cstr!("Sled 0.34.7"));
     lua::setfield(state, -2, lua::cstr!("_VERSION"));
     lua::setglobal!(state, lua::cstr!("sled"));
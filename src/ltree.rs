@@ -4,11 +4,17 @@ use std::ptr::null;
 use lua_shared as lua;
 use lua_shared::lua_State;
 
-use crate::lua_struct::StructError;
-use crate::{check_slice, insert_function, lua_struct, tree_get_key, tree_get_no_arg};
+use crate::buffer::{self, Buffer};
+use crate::codec::SharedCodec;
+use crate::compress;
+use crate::crypto::Cipher;
+use crate::schema::Schema;
+use crate::{
+    check_slice, insert_function, lua_struct, struct_try, tree_get_key, tree_get_no_arg, tree_pop,
+};
 
 #[derive(Debug, Clone)]
-pub struct LTree(pub sled::Tree);
+pub struct LTree(pub sled::Tree, pub SharedCodec);
 impl Deref for LTree {
     type Target = sled::Tree;
     fn deref(&self) -> &Self::Target {
@@ -23,6 +29,10 @@ impl DerefMut for LTree {
 }
 
 impl LTree {
+    fn decode_value(&self, key: &[u8], value: sled::IVec) -> std::io::Result<sled::IVec> {
+        self.1.get().decode(key, value)
+    }
+
     fn lm_name(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
         unsafe {
             let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslt")).cast::<Self>();
@@ -43,7 +53,9 @@ impl LTree {
     fn lm_get(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
         unsafe {
             let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslt")).cast::<Self>();
-            if let Some(ivec) = this.0.get(check_slice!(state, 2))? {
+            let key = check_slice!(state, 2);
+            if let Some(ivec) = this.0.get(key)? {
+                let ivec = this.decode_value(key, ivec)?;
                 lua::pushlstring(state, ivec.as_ptr(), ivec.len());
                 Ok(1)
             } else {
@@ -56,30 +68,28 @@ impl LTree {
         unsafe {
             let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslt")).cast::<Self>();
             let key = check_slice!(state, 2);
-            let fmt = check_slice!(state, 3);
-            if let Some(ivec) = this.0.get(key)? {
-                match lua_struct::unpack(state, fmt, &ivec) {
-                    Ok(args) => Ok(args),
-                    Err(e) => {
-                        drop(ivec);
-                        match e {
-                            StructError::Error(e) => lua::Lerror(state, e),
-                            StructError::ArgError(arg, e) => lua::Largerror(state, arg, e),
-                            StructError::InvalidFormatOption(e, opt) => lua::Lerror(state, e, opt),
-                            StructError::IOError(e) => return Err(e)?,
-                        }
-                    }
-                }
-            } else {
-                Ok(0)
+            if let Some(schema) = Schema::test(state, 3) {
+                return match schema.get(state, &this.0, &this.1.get(), key, false)? {
+                    Some(value) => schema.unpack_values(state, &value),
+                    None => Ok(0),
+                };
             }
+            let fmt = struct_try!(state, lua_struct::check_struct(state, 3));
+            let result = if let Some(ivec) = this.0.get(key)? {
+                lua_struct::unpack(state, &fmt, &this.decode_value(key, ivec)?)
+            } else {
+                return Ok(0);
+            };
+            Ok(struct_try!(state, result))
         }
     }
 
     fn lm_insert(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
         unsafe {
             let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslt")).cast::<Self>();
-            this.insert(check_slice!(state, 2), check_slice!(state, 3))?;
+            let key = check_slice!(state, 2);
+            let value = this.1.get().encode(key, buffer::check_bytes(state, 3))?;
+            this.insert(key, value.as_ref())?;
             Ok(0)
         }
     }
@@ -88,15 +98,64 @@ impl LTree {
         unsafe {
             let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslt")).cast::<Self>();
             let key = check_slice!(state, 2);
-            let fmt = check_slice!(state, 3);
-            let value = match lua_struct::pack(state, fmt, 4) {
-                Ok(result) => result,
-                Err(StructError::Error(e)) => lua::Lerror(state, e),
-                Err(StructError::ArgError(arg, e)) => lua::Largerror(state, arg, e),
-                Err(StructError::InvalidFormatOption(e, opt)) => lua::Lerror(state, e, opt),
-                Err(StructError::IOError(e)) => return Err(e)?,
+            let fmt = struct_try!(state, lua_struct::check_struct(state, 3));
+            let value = match Buffer::test(state, 4) {
+                Some(buffer) => buffer::check_struct_data(&fmt, buffer)?,
+                None => struct_try!(state, lua_struct::pack(state, &fmt, 4)),
             };
-            this.insert(key, value)?;
+            this.insert(key, this.1.get().encode(key, value)?.as_ref())?;
+            Ok(0)
+        }
+    }
+
+    fn lm_get_buffer(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslt")).cast::<Self>();
+            let key = check_slice!(state, 2);
+            if let Some(ivec) = this.0.get(key)? {
+                Buffer::fill_or_push(state, 3, &this.decode_value(key, ivec)?);
+                Ok(1)
+            } else {
+                Ok(0)
+            }
+        }
+    }
+
+    fn lm_get_struct_table(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslt")).cast::<Self>();
+            let key = check_slice!(state, 2);
+            let fmt = struct_try!(state, lua_struct::check_struct(state, 3));
+            let result = if let Some(ivec) = this.0.get(key)? {
+                lua_struct::unpack_table(state, &fmt, &this.decode_value(key, ivec)?)
+            } else {
+                return Ok(0);
+            };
+            Ok(struct_try!(state, result))
+        }
+    }
+
+    fn lm_get_record(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslt")).cast::<Self>();
+            let key = check_slice!(state, 2);
+            let schema = &*lua::Lcheckudata(state, 3, lua::cstr!("cslsc")).cast::<Schema>();
+            let writeback = lua::toboolean(state, 4) as u8 != 0;
+            if let Some(value) = schema.get(state, &this.0, &this.1.get(), key, writeback)? {
+                schema.unpack(state, &value)
+            } else {
+                Ok(0)
+            }
+        }
+    }
+
+    fn lm_insert_record(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslt")).cast::<Self>();
+            let key = check_slice!(state, 2);
+            let schema = &*lua::Lcheckudata(state, 3, lua::cstr!("cslsc")).cast::<Schema>();
+            let value = schema.pack(state, 4)?;
+            this.insert(key, this.1.get().encode(key, &value)?.as_ref())?;
             Ok(0)
         }
     }
@@ -113,9 +172,11 @@ impl LTree {
         unsafe {
             let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslt")).cast::<Self>();
             let mut range = this.range(check_slice!(state, 2)..=check_slice!(state, 3));
+            let codec = this.1.clone();
             lua::pushfunction(state, move |state| {
                 if let Some(tree_name) = range.next() {
                     let (key, value) = tree_name?;
+                    let value = codec.get().decode(&key, value)?;
                     lua::pushlstring(state, key.as_ptr(), key.len());
                     lua::pushlstring(state, value.as_ptr(), value.len());
                     Ok(2)
@@ -135,9 +196,11 @@ impl LTree {
                 std::slice::from_raw_parts(lua::Loptlstring(state, 2, null(), &mut len), len)
             };
             let mut prefix = this.scan_prefix(prefix);
+            let codec = this.1.clone();
             lua::pushfunction(state, move |state| {
                 if let Some(tree_name) = prefix.next() {
                     let (key, value) = tree_name?;
+                    let value = codec.get().decode(&key, value)?;
                     lua::pushlstring(state, key.as_ptr(), key.len());
                     lua::pushlstring(state, value.as_ptr(), value.len());
                     Ok(2)
@@ -149,6 +212,66 @@ impl LTree {
         }
     }
 
+    fn lm_validate_struct(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslt")).cast::<Self>();
+            let fmt = struct_try!(state, lua_struct::check_struct(state, 2));
+            let prefix = {
+                let mut len = 0;
+                std::slice::from_raw_parts(lua::Loptlstring(state, 3, null(), &mut len), len)
+            };
+            lua::createtable(state, 0, 0);
+            let mut invalid = 0;
+            for entry in this.scan_prefix(prefix) {
+                let (key, value) = entry?;
+                let value = this.decode_value(&key, value)?;
+                if let Some(e) = lua_struct::validate(&fmt, &value) {
+                    invalid += 1;
+                    lua::createtable(state, 0, 2);
+                    lua::pushlstring(state, key.as_ptr(), key.len());
+                    lua::setfield(state, -2, lua::cstr!("key"));
+                    lua::pushlstring(state, e.as_ptr(), e.len());
+                    lua::setfield(state, -2, lua::cstr!("error"));
+                    lua::rawseti(state, -2, invalid);
+                }
+            }
+            Ok(1)
+        }
+    }
+
+    /// `SetCompression(algo[, level])`, nil disables compression.
+    /// Values already in the tree are rewritten, setting is kept in the database.
+    fn lm_set_compression(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslt")).cast::<Self>();
+            let compression = if lua::get_type(state, 2) > 0 {
+                Some((
+                    compress::check_algorithm(state, 2),
+                    compress::opt_level(state, 3),
+                ))
+            } else {
+                None
+            };
+            this.1.set_compression(&this.0, compression)?;
+            Ok(0)
+        }
+    }
+
+    /// `SetEncryption(secret)`, key is derived from `secret`. Nil disables encryption.
+    /// Secret of an already encrypted tree unlocks it, a different one re-encrypts values with it.
+    fn lm_set_encryption(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslt")).cast::<Self>();
+            let encryption = if lua::get_type(state, 2) > 0 {
+                Some(Cipher::new(check_slice!(state, 2)))
+            } else {
+                None
+            };
+            this.1.set_encryption(&this.0, encryption)?;
+            Ok(0)
+        }
+    }
+
     fn lm_flush(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
         unsafe {
             let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslt")).cast::<Self>();
@@ -175,7 +298,8 @@ impl LTree {
     }
 
     tree_get_key!(get_lt get_gt, "cslt");
-    tree_get_no_arg!(first last pop_max pop_min, "cslt");
+    tree_get_no_arg!(first last, "cslt");
+    tree_pop!(pop_max pop_min, "cslt");
 
     fn __gc(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
         unsafe {
@@ -195,11 +319,18 @@ impl LTree {
             insert_function!(state, "Clear", Self::lm_clear);
             insert_function!(state, "Get", Self::lm_get);
             insert_function!(state, "GetStruct", Self::lm_get_struct);
+            insert_function!(state, "GetStructTable", Self::lm_get_struct_table);
+            insert_function!(state, "GetBuffer", Self::lm_get_buffer);
             insert_function!(state, "Insert", Self::lm_insert);
             insert_function!(state, "InsertStruct", Self::lm_insert_struct);
+            insert_function!(state, "GetRecord", Self::lm_get_record);
+            insert_function!(state, "InsertRecord", Self::lm_insert_record);
             insert_function!(state, "Remove", Self::lm_remove);
             insert_function!(state, "Range", Self::lm_range);
             insert_function!(state, "ScanPrefix", Self::lm_scan_prefix);
+            insert_function!(state, "ValidateStruct", Self::lm_validate_struct);
+            insert_function!(state, "SetCompression", Self::lm_set_compression);
+            insert_function!(state, "SetEncryption", Self::lm_set_encryption);
             insert_function!(state, "Flush", Self::lm_flush);
             insert_function!(state, "Checksum", Self::lm_checksum);
             insert_function!(state, "ContainsKey", Self::lm_contains_key);
@@ -1,4 +1,4 @@
-use std::ffi::c_void;
+use std::borrow::Cow;
 use std::io::{self, Cursor};
 use std::io::{Read, Seek, Write};
 use std::os::raw::c_uint;
@@ -6,9 +6,11 @@ use std::os::raw::c_uint;
 use lua::lua_State;
 use lua_shared as lua;
 
-use crate::check_slice;
+use crate::decimal::{self, Decimal};
+use crate::gmod;
+use crate::{check_slice, insert_function};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum KOption {
     I8,
     U8,
@@ -19,11 +21,27 @@ enum KOption {
     Usize,
     Float,
     Double,
+    Varint,
+    ZigZag,
+    Bool,
+    Vector,
+    Angle,
+    Color,
+    Array(Struct),
+    Decimal(u8),
     Char,
     String,
+    // Lua 5.3 `string.pack` options.
+    Int(bool),
+    CharExact,
+    SizedString,
+    ZString,
+    Padding,
+    Align,
     NOP,
 }
 
+#[derive(Debug, Clone, Copy)]
 enum Endianness {
     Little,
     Big,
@@ -31,11 +49,42 @@ enum Endianness {
 }
 
 struct ReaderState<'a> {
-    state: *mut c_void,
     endianness: Endianness,
+    // Set by '^', applies to the next option.
+    optional: bool,
+    // Set by '!' in Lua 5.3 mode.
+    maxalign: usize,
     fmt: &'a [u8],
 }
 
+/// Single compiled format option.
+#[derive(Debug, Clone)]
+struct Field {
+    option: KOption,
+    endianness: Endianness,
+    size: usize,
+    // Optional fields may be nil, their presence is tracked
+    // in a bitmap written in front of the packed data.
+    optional: bool,
+    // Lua 5.3 mode only, field is preceded by zero bytes up to multiple of `align`.
+    align: usize,
+}
+
+impl Field {
+    // Padding and alignment options neither consume nor produce lua values.
+    fn has_value(&self) -> bool {
+        !matches!(self.option, KOption::Padding | KOption::Align)
+    }
+}
+
+/// Format string parsed once into a list of options.
+/// Exposed to lua as `sled.Struct(fmt)`.
+///
+/// Formats starting with `#` follow Lua 5.3 `string.pack` rules instead,
+/// the second field is set for them.
+#[derive(Debug, Clone, Default)]
+pub struct Struct(Vec<Field>, bool);
+
 fn is_digit(byte: u8) -> bool {
     byte ^ b'0' < 10
 }
@@ -67,6 +116,26 @@ impl From<std::io::Error> for StructError {
     }
 }
 
+impl std::fmt::Display for StructError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = |e| unsafe { std::ffi::CStr::from_ptr(e as *const _).to_string_lossy() };
+        match self {
+            StructError::Error(e) => write!(f, "{}", message(*e)),
+            StructError::ArgError(arg, e) => write!(f, "bad argument #{} ({})", arg, message(*e)),
+            StructError::InvalidFormatOption(e, opt) => write!(
+                f,
+                "{}",
+                message(*e).replace("%c", &char::from(*opt as u8).to_string())
+            ),
+            StructError::IOError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+// Struct errors are returned to lua like any other error instead of being raised in place,
+// raising would longjmp over owned values of the caller without dropping them.
+impl std::error::Error for StructError {}
+
 unsafe fn get_option(state: &mut ReaderState) -> Result<Option<(KOption, usize)>, StructError> {
     if state.fmt.len() == 0 {
         return Ok(None);
@@ -84,6 +153,36 @@ unsafe fn get_option(state: &mut ReaderState) -> Result<Option<(KOption, usize)>
         b'f' => Ok(Some((KOption::Float, std::mem::size_of::<f32>()))),
         b'd' => Ok(Some((KOption::Double, std::mem::size_of::<f64>()))),
         b'n' => Ok(Some((KOption::Double, std::mem::size_of::<f64>()))),
+        b'?' => Ok(Some((KOption::Bool, std::mem::size_of::<u8>()))),
+        b'u' => Ok(Some((KOption::Varint, 1))),
+        b'z' => Ok(Some((KOption::ZigZag, 1))),
+        b'v' => Ok(Some((KOption::Vector, 3 * std::mem::size_of::<f32>()))),
+        b'V' => Ok(Some((KOption::Vector, 3 * std::mem::size_of::<f64>()))),
+        b'a' => Ok(Some((KOption::Angle, 3 * std::mem::size_of::<f32>()))),
+        b'A' => Ok(Some((KOption::Angle, 3 * std::mem::size_of::<f64>()))),
+        b'C' => Ok(Some((KOption::Color, 4))),
+        b'*' => {
+            if state.fmt.first() != Some(&b'(') {
+                return Err(StructError::Error(lua::cstr!("missing '(' after '*'")));
+            }
+            let mut depth = 0;
+            let end = state.fmt.iter().position(|&byte| {
+                match byte {
+                    b'(' => depth += 1,
+                    b')' => depth -= 1,
+                    _ => {}
+                }
+                depth == 0
+            });
+            match end {
+                Some(end) => {
+                    let group = compile_with(&state.fmt[1..end], state.endianness)?;
+                    state.fmt = &state.fmt[end + 1..];
+                    Ok(Some((KOption::Array(group), std::mem::size_of::<u16>())))
+                }
+                None => Err(StructError::Error(lua::cstr!("unfinished group"))),
+            }
+        }
         b's' => Ok(Some((KOption::String, std::mem::size_of::<u16>()))),
         b'c' => match read_number(state) {
             Some(len) => Ok(Some((KOption::Char, len))),
@@ -91,7 +190,23 @@ unsafe fn get_option(state: &mut ReaderState) -> Result<Option<(KOption, usize)>
                 "missing size for format option 'c'"
             ))),
         },
+        b'D' => match read_number(state) {
+            Some(scale) if scale <= decimal::MAX_SCALE as usize => Ok(Some((
+                KOption::Decimal(scale as u8),
+                std::mem::size_of::<i64>(),
+            ))),
+            Some(_) => Err(StructError::Error(lua::cstr!(
+                "scale for format option 'D' is too large"
+            ))),
+            None => Err(StructError::Error(lua::cstr!(
+                "missing scale for format option 'D'"
+            ))),
+        },
         b' ' => Ok(Some((KOption::NOP, 0))),
+        b'^' => {
+            state.optional = true;
+            Ok(Some((KOption::NOP, 0)))
+        }
         b'<' => {
             state.endianness = Endianness::Little;
             Ok(Some((KOption::NOP, 0)))
@@ -111,13 +226,220 @@ unsafe fn get_option(state: &mut ReaderState) -> Result<Option<(KOption, usize)>
     }
 }
 
+// Same as in Lua 5.3, alignment of `struct { char c; union { double d; void *p; i64 i; } }`.
+const NATIVE_ALIGN: usize = 8;
+const MAX_INT_SIZE: usize = 16;
+
+unsafe fn read_int_size(state: &mut ReaderState, default: usize) -> Result<usize, StructError> {
+    match read_number(state).unwrap_or(default) {
+        size @ 1..=MAX_INT_SIZE => Ok(size),
+        _ => Err(StructError::Error(lua::cstr!(
+            "integral size out of limits [1,16]"
+        ))),
+    }
+}
+
+/// Lua 5.3 `string.pack` options.
+/// Returns option, its size and alignment it asks for.
+unsafe fn get_option_lua53(
+    state: &mut ReaderState,
+) -> Result<Option<(KOption, usize, usize)>, StructError> {
+    if state.fmt.len() == 0 {
+        return Ok(None);
+    }
+    let (opt, rest) = state.fmt.split_at(1);
+    state.fmt = rest;
+    let int = |signed, size| Ok(Some((KOption::Int(signed), size, size)));
+    match opt[0] {
+        b'b' => int(true, std::mem::size_of::<i8>()),
+        b'B' => int(false, std::mem::size_of::<u8>()),
+        b'h' => int(true, std::mem::size_of::<i16>()),
+        b'H' => int(false, std::mem::size_of::<u16>()),
+        b'l' => int(true, std::mem::size_of::<std::os::raw::c_long>()),
+        b'L' => int(false, std::mem::size_of::<std::os::raw::c_ulong>()),
+        b'j' => int(true, std::mem::size_of::<i64>()),
+        b'J' => int(false, std::mem::size_of::<u64>()),
+        b'T' => int(false, std::mem::size_of::<usize>()),
+        b'i' => int(true, read_int_size(state, std::mem::size_of::<i32>())?),
+        b'I' => int(false, read_int_size(state, std::mem::size_of::<u32>())?),
+        b'f' => Ok(Some((KOption::Float, 4, 4))),
+        b'd' | b'n' => Ok(Some((KOption::Double, 8, 8))),
+        b's' => {
+            let size = read_int_size(state, std::mem::size_of::<usize>())?;
+            Ok(Some((KOption::SizedString, size, size)))
+        }
+        b'c' => match read_number(state) {
+            Some(len) => Ok(Some((KOption::CharExact, len, 0))),
+            None => Err(StructError::Error(lua::cstr!(
+                "missing size for format option 'c'"
+            ))),
+        },
+        b'z' => Ok(Some((KOption::ZString, 0, 0))),
+        b'x' => Ok(Some((KOption::Padding, 1, 1))),
+        b'X' => match get_option_lua53(state)? {
+            Some((KOption::CharExact, ..)) | Some((_, _, 0)) | None => Err(StructError::Error(
+                lua::cstr!("invalid next option for option 'X'"),
+            )),
+            Some((_, _, align)) => Ok(Some((KOption::Align, 0, align))),
+        },
+        b' ' => Ok(Some((KOption::NOP, 0, 0))),
+        b'<' => {
+            state.endianness = Endianness::Little;
+            Ok(Some((KOption::NOP, 0, 0)))
+        }
+        b'>' => {
+            state.endianness = Endianness::Big;
+            Ok(Some((KOption::NOP, 0, 0)))
+        }
+        b'=' => {
+            state.endianness = Endianness::Native;
+            Ok(Some((KOption::NOP, 0, 0)))
+        }
+        b'!' => {
+            state.maxalign = read_int_size(state, NATIVE_ALIGN)?;
+            Ok(Some((KOption::NOP, 0, 0)))
+        }
+        token @ _ => Err(StructError::InvalidFormatOption(
+            lua::cstr!("invalid format option '%c'"),
+            token as c_uint,
+        )),
+    }
+}
+
+pub fn compile(fmt: &[u8]) -> Result<Struct, StructError> {
+    match fmt.split_first() {
+        Some((b'#', rest)) => compile_lua53(rest),
+        _ => compile_with(fmt, Endianness::Native),
+    }
+}
+
+fn compile_lua53(fmt: &[u8]) -> Result<Struct, StructError> {
+    unsafe {
+        let mut reader_state = ReaderState {
+            endianness: Endianness::Native,
+            optional: false,
+            maxalign: 1,
+            fmt: fmt,
+        };
+        let mut fields = Vec::new();
+        while let Some((option, size, align)) = get_option_lua53(&mut reader_state)? {
+            if let KOption::NOP = option {
+                continue;
+            }
+            let align = match align.min(reader_state.maxalign) {
+                align if align <= 1 => 0,
+                align if align & (align - 1) != 0 => {
+                    return Err(StructError::Error(lua::cstr!(
+                        "format asks for alignment not power of 2"
+                    )))
+                }
+                align => align,
+            };
+            fields.push(Field {
+                option,
+                endianness: reader_state.endianness,
+                size,
+                optional: false,
+                align,
+            });
+        }
+        Ok(Struct(fields, true))
+    }
+}
+
+fn compile_with(fmt: &[u8], endianness: Endianness) -> Result<Struct, StructError> {
+    unsafe {
+        let mut reader_state = ReaderState {
+            endianness,
+            optional: false,
+            maxalign: 1,
+            fmt: fmt,
+        };
+        let mut fields = Vec::new();
+        while let Some((option, size)) = get_option(&mut reader_state)? {
+            if let KOption::NOP = option {
+                continue;
+            }
+            fields.push(Field {
+                option,
+                endianness: reader_state.endianness,
+                size,
+                optional: std::mem::take(&mut reader_state.optional),
+                align: 0,
+            });
+        }
+        if reader_state.optional {
+            return Err(StructError::Error(lua::cstr!(
+                "missing format option after '^'"
+            )));
+        }
+        Ok(Struct(fields, false))
+    }
+}
+
+/// Accepts either format string or `sled.Struct` object.
+pub unsafe fn check_struct<'a>(
+    state: lua_State,
+    index: i32,
+) -> Result<Cow<'a, Struct>, StructError> {
+    if lua::get_type(state, index) == 7 {
+        Ok(Cow::Borrowed(
+            &*lua::Lcheckudata(state, index, lua::cstr!("csls")).cast::<Struct>(),
+        ))
+    } else {
+        compile(check_slice!(state, index)).map(Cow::Owned)
+    }
+}
+
+pub const MAX_VARINT_SIZE: usize = 10;
+
+/// Encodes `value` as unsigned LEB128.
+pub fn write_varint(mut value: u64, out: &mut [u8; MAX_VARINT_SIZE]) -> &[u8] {
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out[len] = byte;
+            return &out[..len + 1];
+        }
+        out[len] = byte | 0x80;
+        len += 1;
+    }
+}
+
+/// Decodes unsigned LEB128, returns value and amount of bytes it took.
+/// `None` if data ends before the last byte or varint is longer than 64 bits.
+pub fn read_varint(data: &[u8]) -> Option<(u64, usize)> {
+    let mut result = 0u64;
+    for (i, byte) in data.iter().take(MAX_VARINT_SIZE).enumerate() {
+        // Only the lowest bit of the 10th byte still fits.
+        if i == MAX_VARINT_SIZE - 1 && *byte > 0x01 {
+            return None;
+        }
+        result |= ((byte & 0x7F) as u64) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Some((result, i + 1));
+        }
+    }
+    None
+}
+
+pub fn zigzag_encode(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+pub fn zigzag_decode(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
 // I don't fucking care. luaL_Buffer is allocated on the stack.
 // At the same time, this buffer is two times bigger than lua's string buffer.
 static mut STRING_BUFFER: [u8; 65536] = [0; 65536];
 
 macro_rules! pack_number {
-    ($state:ident, $buffer:ident, $value:tt) => {
-        match $state.endianness {
+    ($field:ident, $buffer:ident, $value:tt) => {
+        match $field.endianness {
             Endianness::Little => $buffer.write(&$value.to_le_bytes())?,
             Endianness::Big => $buffer.write(&$value.to_be_bytes())?,
             Endianness::Native => $buffer.write(&$value.to_ne_bytes())?,
@@ -126,10 +448,10 @@ macro_rules! pack_number {
 }
 
 macro_rules! unpack_number {
-    ($state:ident, $buffer:ident, $typ:ty) => {{
+    ($field:ident, $buffer:ident, $typ:ty) => {{
         let mut data = [0; std::mem::size_of::<$typ>()];
         $buffer.read(&mut data)?;
-        match $state.endianness {
+        match $field.endianness {
             Endianness::Little => <$typ>::from_le_bytes(data),
             Endianness::Big => <$typ>::from_be_bytes(data),
             Endianness::Native => <$typ>::from_ne_bytes(data),
@@ -137,66 +459,284 @@ macro_rules! unpack_number {
     }};
 }
 
-pub fn pack(state: lua_State, fmt: &[u8], start: i32) -> Result<&'static [u8], StructError> {
+// Lua 5.3 integers are stored least significant byte first, then reordered.
+fn order_bytes(bytes: &mut [u8], endianness: Endianness) {
+    match endianness {
+        Endianness::Big => bytes.reverse(),
+        Endianness::Native if cfg!(target_endian = "big") => bytes.reverse(),
+        _ => {}
+    }
+}
+
+fn pack_int(
+    value: i64,
+    field: &Field,
+    signed: bool,
+    buffer: &mut Cursor<&mut [u8]>,
+) -> io::Result<()> {
+    let mut bytes = [if signed && value < 0 { 0xFF } else { 0 }; MAX_INT_SIZE];
+    let len = field.size.min(8);
+    bytes[..len].copy_from_slice(&value.to_le_bytes()[..len]);
+    order_bytes(&mut bytes[..field.size], field.endianness);
+    buffer.write_all(&bytes[..field.size])
+}
+
+/// Converts lua number to integer that fits into `field`, with Lua 5.3 errors.
+fn check_int(value: f64, field: &Field, signed: bool, arg: i32) -> Result<i64, StructError> {
+    if value.fract() != 0.0 || !value.is_finite() {
+        return Err(StructError::ArgError(
+            arg,
+            lua::cstr!("number has no integer representation"),
+        ));
+    }
+    // 2^63 is exact as a double, anything from there on would saturate.
+    if !(-9223372036854775808.0..9223372036854775808.0).contains(&value) {
+        return Err(StructError::ArgError(arg, lua::cstr!("integer overflow")));
+    }
+    let value = value as i64;
+    if field.size < 8 {
+        let limit = 1i64 << (field.size * 8 - 1);
+        if signed && !(-limit..limit).contains(&value) {
+            return Err(StructError::ArgError(arg, lua::cstr!("integer overflow")));
+        } else if !signed && (value as u64) >= (limit as u64) << 1 {
+            return Err(StructError::ArgError(arg, lua::cstr!("unsigned overflow")));
+        }
+    }
+    Ok(value)
+}
+
+fn unpack_int(field: &Field, signed: bool, buffer: &mut Cursor<&[u8]>) -> Result<i64, StructError> {
+    let mut bytes = [0; MAX_INT_SIZE];
+    buffer.read_exact(&mut bytes[..field.size])?;
+    order_bytes(&mut bytes[..field.size], field.endianness);
+    let mut data = [0; 8];
+    data[..field.size.min(8)].copy_from_slice(&bytes[..field.size.min(8)]);
+    let mut value = i64::from_le_bytes(data);
+    if field.size < 8 && signed {
+        let shift = 64 - field.size * 8;
+        value = value << shift >> shift;
+    }
+    let extension = if signed && value < 0 { 0xFF } else { 0 };
+    if bytes[8.min(field.size)..field.size]
+        .iter()
+        .any(|byte| *byte != extension)
+    {
+        return Err(StructError::Error(lua::cstr!(
+            "integer does not fit into Lua Integer"
+        )));
+    }
+    Ok(value)
+}
+
+/// Unpacks an integer option as the number Lua sees. Like Lua 5.3, unsigned 8-byte values
+/// come back as signed integers, so `ff ff ff ff ff ff ff ff` is -1.
+fn unpack_number(
+    field: &Field,
+    signed: bool,
+    buffer: &mut Cursor<&[u8]>,
+) -> Result<f64, StructError> {
+    Ok(unpack_int(field, signed, buffer)? as f64)
+}
+
+/// Writes zero bytes up to the next multiple of `align`, which is at most `MAX_INT_SIZE`.
+fn write_padding(buffer: &mut Cursor<&mut [u8]>, align: usize) -> Result<(), StructError> {
+    let offset = buffer.seek(std::io::SeekFrom::Current(0))? as usize;
+    let padding = (align - offset % align) % align;
+    if buffer.get_ref().len() - offset < padding {
+        return Err(StructError::Error(lua::cstr!("buffer overflow")));
+    }
+    buffer.write_all(&[0; MAX_INT_SIZE][..padding])?;
+    Ok(())
+}
+
+pub fn pack(state: lua_State, fmt: &Struct, start: i32) -> Result<&'static [u8], StructError> {
     unsafe {
-        let mut arg = start;
-        let mut reader_state = ReaderState {
-            state: state,
-            endianness: Endianness::Native,
-            fmt: fmt,
-        };
         let mut buffer = Cursor::new(&mut STRING_BUFFER[..]);
-        while let Some((option, size)) = get_option(&mut reader_state)? {
-            if let KOption::NOP = option {
-                continue;
+        pack_into(state, fmt, start, &mut buffer)?;
+        let len = buffer.seek(std::io::SeekFrom::Current(0))? as usize;
+        Ok(&STRING_BUFFER[..len])
+    }
+}
+
+/// Same as `pack`, but aligns as if the packed bytes were written at `offset`.
+pub fn pack_at(
+    state: lua_State,
+    fmt: &Struct,
+    start: i32,
+    offset: usize,
+) -> Result<&'static [u8], StructError> {
+    unsafe {
+        // Alignments are powers of 2 up to `MAX_INT_SIZE`, so padding only depends on this.
+        let skip = offset % MAX_INT_SIZE;
+        let mut buffer = Cursor::new(&mut STRING_BUFFER[..]);
+        buffer.set_position(skip as u64);
+        pack_into(state, fmt, start, &mut buffer)?;
+        let len = buffer.seek(std::io::SeekFrom::Current(0))? as usize;
+        Ok(&STRING_BUFFER[skip..len])
+    }
+}
+
+fn pack_into(
+    state: lua_State,
+    fmt: &Struct,
+    start: i32,
+    buffer: &mut Cursor<&mut [u8]>,
+) -> Result<(), StructError> {
+    unsafe {
+        let mut arg = start;
+        let bitmap = buffer.seek(std::io::SeekFrom::Current(0))? as usize;
+        let mut bit = 0;
+        if buffer.get_ref().len() - bitmap < fmt.bitmap_size() {
+            return Err(StructError::Error(lua::cstr!("buffer overflow")));
+        }
+        for _ in 0..fmt.bitmap_size() {
+            buffer.write(&[0])?;
+        }
+        for field in fmt.0.iter() {
+            if field.align > 0 {
+                write_padding(buffer, field.align)?;
             }
-            if STRING_BUFFER.len() - (buffer.seek(std::io::SeekFrom::Current(0))? as usize) < size {
+            if buffer.get_ref().len() - (buffer.seek(std::io::SeekFrom::Current(0))? as usize)
+                < field.size
+            {
                 return Err(StructError::Error(lua::cstr!("buffer overflow")));
             }
-            match option {
+            if field.optional {
+                let present = lua::get_type(state, arg) > 0;
+                if present {
+                    buffer.get_mut()[bitmap + bit / 8] |= 1 << (bit % 8);
+                }
+                bit += 1;
+                if !present {
+                    arg += 1;
+                    continue;
+                }
+            }
+            match field.option {
                 KOption::I8 => {
-                    let value = lua::Lcheckinteger(reader_state.state, arg) as i8;
-                    pack_number!(reader_state, buffer, value);
+                    let value = lua::Lcheckinteger(state, arg) as i8;
+                    pack_number!(field, buffer, value);
                 }
                 KOption::U8 => {
-                    let value = lua::Lcheckinteger(reader_state.state, arg) as u8;
-                    pack_number!(reader_state, buffer, value);
+                    let value = lua::Lcheckinteger(state, arg) as u8;
+                    pack_number!(field, buffer, value);
                 }
                 KOption::I16 => {
-                    let value = lua::Lcheckinteger(reader_state.state, arg) as i16;
-                    pack_number!(reader_state, buffer, value);
+                    let value = lua::Lcheckinteger(state, arg) as i16;
+                    pack_number!(field, buffer, value);
                 }
                 KOption::U16 => {
-                    let value = lua::Lcheckinteger(reader_state.state, arg) as u16;
-                    pack_number!(reader_state, buffer, value);
+                    let value = lua::Lcheckinteger(state, arg) as u16;
+                    pack_number!(field, buffer, value);
                 }
                 KOption::I32 => {
-                    let value = lua::Lcheckinteger(reader_state.state, arg) as i32;
-                    pack_number!(reader_state, buffer, value);
+                    let value = lua::Lcheckinteger(state, arg) as i32;
+                    pack_number!(field, buffer, value);
                 }
                 KOption::U32 => {
-                    let value = lua::Lcheckinteger(reader_state.state, arg) as u32;
-                    pack_number!(reader_state, buffer, value);
+                    let value = lua::Lcheckinteger(state, arg) as u32;
+                    pack_number!(field, buffer, value);
                 }
                 KOption::Usize => {
-                    let value = lua::Lcheckinteger(reader_state.state, arg) as usize;
-                    pack_number!(reader_state, buffer, value);
+                    let value = lua::Lcheckinteger(state, arg) as usize;
+                    pack_number!(field, buffer, value);
                 }
                 KOption::Float => {
-                    let value = lua::Lchecknumber(reader_state.state, arg) as f32;
-                    pack_number!(reader_state, buffer, value);
+                    let value = lua::Lchecknumber(state, arg) as f32;
+                    pack_number!(field, buffer, value);
                 }
                 KOption::Double => {
-                    let value = lua::Lchecknumber(reader_state.state, arg);
-                    pack_number!(reader_state, buffer, value);
+                    let value = lua::Lchecknumber(state, arg);
+                    pack_number!(field, buffer, value);
+                }
+                KOption::Varint | KOption::ZigZag => {
+                    let value = lua::Lchecknumber(state, arg);
+                    let value = match field.option {
+                        KOption::Varint if value < 0.0 => {
+                            return Err(StructError::ArgError(
+                                arg,
+                                lua::cstr!("unsigned varint can't be negative"),
+                            ))
+                        }
+                        KOption::Varint => value as u64,
+                        _ => zigzag_encode(value as i64),
+                    };
+                    let mut varint = [0; MAX_VARINT_SIZE];
+                    let varint = write_varint(value, &mut varint);
+                    if buffer.get_ref().len()
+                        - (buffer.seek(std::io::SeekFrom::Current(0))? as usize)
+                        < varint.len()
+                    {
+                        return Err(StructError::Error(lua::cstr!("buffer overflow")));
+                    }
+                    buffer.write(varint)?;
+                }
+                KOption::Bool => {
+                    buffer.write(&[(lua::toboolean(state, arg) as u8 != 0) as u8])?;
+                }
+                KOption::Vector | KOption::Angle => {
+                    let value = match field.option {
+                        KOption::Vector => gmod::check_vector(state, arg),
+                        _ => gmod::check_angle(state, arg),
+                    };
+                    for component in value {
+                        if field.size == 3 * std::mem::size_of::<f32>() {
+                            let component = component as f32;
+                            pack_number!(field, buffer, component);
+                        } else {
+                            pack_number!(field, buffer, component);
+                        }
+                    }
+                }
+                KOption::Color => {
+                    buffer.write(&gmod::check_color(state, arg))?;
+                }
+                KOption::Decimal(scale) => {
+                    let value = match decimal::to_decimal(state, arg)
+                        .and_then(|value| value.with_scale(scale))
+                    {
+                        Some(value) => value.value(),
+                        None => {
+                            return Err(StructError::ArgError(
+                                arg,
+                                lua::cstr!("decimal expected or too many decimal places"),
+                            ))
+                        }
+                    };
+                    pack_number!(field, buffer, value);
+                }
+                KOption::Array(ref group) => {
+                    lua::Lchecktype(state, arg, 5);
+                    let count = lua::objlen(state, arg) as usize;
+                    if count > u16::MAX as usize {
+                        return Err(StructError::ArgError(arg, lua::cstr!("too many elements")));
+                    }
+                    pack_number!(field, buffer, (count as u16));
+                    lua::Lcheckstack(state, group.len() as i32 + 1, lua::cstr!("group too large"));
+                    let top = lua::gettop(state);
+                    for i in 1..=count as i32 {
+                        lua::rawgeti(state, arg, i);
+                        if lua::get_type(state, top + 1) != 5 {
+                            return Err(StructError::ArgError(
+                                arg,
+                                lua::cstr!("array of tables expected"),
+                            ));
+                        }
+                        for j in 1..=group.len() as i32 {
+                            lua::rawgeti(state, top + 1, j);
+                        }
+                        let result = pack_into(state, group, top + 2, buffer);
+                        lua::settop(state, top);
+                        result?;
+                    }
                 }
                 KOption::Char => {
                     let str = check_slice!(state, arg);
-                    if str.len() >= size {
-                        buffer.write(&str[..size])?;
+                    if str.len() >= field.size {
+                        buffer.write(&str[..field.size])?;
                     } else {
                         buffer.write(str)?;
-                        for _ in 0..size - str.len() {
+                        for _ in 0..field.size - str.len() {
                             buffer.write(&[0])?;
                         }
                     }
@@ -204,86 +744,260 @@ pub fn pack(state: lua_State, fmt: &[u8], start: i32) -> Result<&'static [u8], S
                 KOption::String => {
                     let str = check_slice!(state, arg);
                     if (str.len() > 1 << 16 - 2)
-                        || ((buffer.seek(std::io::SeekFrom::Current(0))? as usize) + size
-                            > STRING_BUFFER.len())
+                        || ((buffer.seek(std::io::SeekFrom::Current(0))? as usize) + field.size
+                            > buffer.get_ref().len())
                     {
                         return Err(StructError::ArgError(
                             arg,
                             lua::cstr!("string won't fit in the buffer"),
                         ));
                     }
-                    pack_number!(reader_state, buffer, (str.len() as u16));
+                    pack_number!(field, buffer, (str.len() as u16));
                     buffer.write(str)?;
                 }
+                KOption::Int(signed) => {
+                    let value = check_int(lua::Lchecknumber(state, arg), field, signed, arg)?;
+                    pack_int(value, field, signed, buffer)?;
+                }
+                KOption::CharExact => {
+                    let str = check_slice!(state, arg);
+                    if str.len() > field.size {
+                        return Err(StructError::ArgError(
+                            arg,
+                            lua::cstr!("string longer than given size"),
+                        ));
+                    }
+                    buffer.write_all(str)?;
+                    for _ in 0..field.size - str.len() {
+                        buffer.write_all(&[0])?;
+                    }
+                }
+                KOption::SizedString | KOption::ZString => {
+                    let str = check_slice!(state, arg);
+                    let offset = buffer.seek(std::io::SeekFrom::Current(0))? as usize;
+                    if let KOption::ZString = field.option {
+                        if str.contains(&0) {
+                            return Err(StructError::ArgError(
+                                arg,
+                                lua::cstr!("string contains zeros"),
+                            ));
+                        }
+                    } else if field.size < 8 && str.len() as u64 >= 1 << (field.size * 8) {
+                        return Err(StructError::ArgError(
+                            arg,
+                            lua::cstr!("string length does not fit in given size"),
+                        ));
+                    }
+                    if offset + field.size + str.len() + 1 > buffer.get_ref().len() {
+                        return Err(StructError::ArgError(
+                            arg,
+                            lua::cstr!("string won't fit in the buffer"),
+                        ));
+                    }
+                    if let KOption::ZString = field.option {
+                        buffer.write_all(str)?;
+                        buffer.write_all(&[0])?;
+                    } else {
+                        pack_int(str.len() as i64, field, false, buffer)?;
+                        buffer.write_all(str)?;
+                    }
+                }
+                KOption::Padding => {
+                    buffer.write_all(&[0])?;
+                    continue;
+                }
+                KOption::Align => continue,
                 KOption::NOP => {}
             }
             arg += 1;
         }
+        Ok(())
+    }
+}
+
+pub fn unpack(state: lua_State, fmt: &Struct, data: &[u8]) -> Result<i32, StructError> {
+    unpack_from(state, fmt, data).map(|(nrets, _)| nrets)
+}
 
-        Ok(&STRING_BUFFER[..(buffer.seek(std::io::SeekFrom::Current(0))? as usize)])
+/// Same as `unpack`, but pushes single table with all values instead.
+pub fn unpack_table(state: lua_State, fmt: &Struct, data: &[u8]) -> Result<i32, StructError> {
+    unsafe {
+        lua::createtable(state, fmt.len() as _, 0);
+        let table = lua::gettop(state);
+        unpack_into(state, fmt, &mut Cursor::new(data), Some(table))?;
+        Ok(1)
     }
 }
 
-pub fn unpack(state: lua_State, fmt: &[u8], data: &[u8]) -> Result<i32, StructError> {
+/// Same as `unpack`, but also returns amount of consumed bytes.
+pub fn unpack_from(
+    state: lua_State,
+    fmt: &Struct,
+    data: &[u8],
+) -> Result<(i32, usize), StructError> {
+    unpack_at(state, fmt, data, 0)
+}
+
+/// Unpacks starting at `pos`, returns position right after the last read byte.
+/// Alignment is counted from the start of `data`, as in Lua 5.3 `string.unpack`.
+pub fn unpack_at(
+    state: lua_State,
+    fmt: &Struct,
+    data: &[u8],
+    pos: usize,
+) -> Result<(i32, usize), StructError> {
     unsafe {
-        let mut nrets = 0;
-        let mut reader_state = ReaderState {
-            state: state,
-            endianness: Endianness::Native,
-            fmt: fmt,
-        };
         let mut buffer = Cursor::new(data);
-        while let Some((option, size)) = get_option(&mut reader_state)? {
-            if let KOption::NOP = option {
-                continue;
+        buffer.set_position(pos as u64);
+        let nrets = unpack_into(state, fmt, &mut buffer, None)?;
+        Ok((nrets, buffer.seek(std::io::SeekFrom::Current(0))? as usize))
+    }
+}
+
+/// Pushes values on the stack, or stores them in `table` at absolute stack index.
+fn unpack_into<'a>(
+    state: lua_State,
+    fmt: &Struct,
+    buffer: &mut Cursor<&'a [u8]>,
+    table: Option<i32>,
+) -> Result<i32, StructError> {
+    unsafe {
+        let data: &'a [u8] = *buffer.get_ref();
+        let mut nrets = 0;
+        let bitmap = buffer.seek(std::io::SeekFrom::Current(0))? as usize;
+        let bitmap_size = fmt.bitmap_size();
+        if data.len() - bitmap < bitmap_size {
+            return Err(StructError::Error(lua::cstr!("data string too short")));
+        }
+        buffer.seek(std::io::SeekFrom::Current(bitmap_size as _))?;
+        let mut bit = 0;
+        for field in fmt.0.iter() {
+            // Vectors, angles and colors need room for the constructor call.
+            if lua::checkstack(state, 5) == 0 {
+                return Err(StructError::Error(lua::cstr!("too many results to unpack")));
+            }
+            if field.optional {
+                let present = data[bitmap + bit / 8] & (1 << (bit % 8)) != 0;
+                bit += 1;
+                if !present {
+                    nrets += 1;
+                    if table.is_none() {
+                        lua::pushnil(state);
+                    }
+                    continue;
+                }
             }
-            if data.len() - (buffer.seek(std::io::SeekFrom::Current(0))? as usize) < size {
+            if field.align > 0 {
+                let offset = buffer.seek(std::io::SeekFrom::Current(0))? as usize;
+                let padding = (field.align - offset % field.align) % field.align;
+                if data.len() - offset < padding {
+                    return Err(StructError::Error(lua::cstr!("data string too short")));
+                }
+                buffer.seek(std::io::SeekFrom::Current(padding as _))?;
+            }
+            if data.len() - (buffer.seek(std::io::SeekFrom::Current(0))? as usize) < field.size {
                 return Err(StructError::Error(lua::cstr!("data string too short")));
             }
+            if !field.has_value() {
+                buffer.seek(std::io::SeekFrom::Current(field.size as _))?;
+                continue;
+            }
             nrets += 1;
-            match option {
+            match field.option {
                 KOption::I8 => {
-                    let value = unpack_number!(reader_state, buffer, i8);
+                    let value = unpack_number!(field, buffer, i8);
                     lua::pushinteger(state, value as _);
                 }
                 KOption::U8 => {
-                    let value = unpack_number!(reader_state, buffer, u8);
+                    let value = unpack_number!(field, buffer, u8);
                     lua::pushinteger(state, value as _);
                 }
                 KOption::I16 => {
-                    let value = unpack_number!(reader_state, buffer, i16);
+                    let value = unpack_number!(field, buffer, i16);
                     lua::pushinteger(state, value as _);
                 }
                 KOption::U16 => {
-                    let value = unpack_number!(reader_state, buffer, u16);
+                    let value = unpack_number!(field, buffer, u16);
                     lua::pushinteger(state, value as _);
                 }
                 KOption::I32 => {
-                    let value = unpack_number!(reader_state, buffer, i32);
+                    let value = unpack_number!(field, buffer, i32);
                     lua::pushinteger(state, value as _);
                 }
                 KOption::U32 => {
-                    let value = unpack_number!(reader_state, buffer, u32);
+                    let value = unpack_number!(field, buffer, u32);
                     lua::pushinteger(state, value as _);
                 }
                 KOption::Usize => {
-                    let value = unpack_number!(reader_state, buffer, usize);
+                    let value = unpack_number!(field, buffer, usize);
                     lua::pushinteger(state, value as _);
                 }
                 KOption::Float => {
-                    let value = unpack_number!(reader_state, buffer, f32);
+                    let value = unpack_number!(field, buffer, f32);
                     lua::pushnumber(state, value as _);
                 }
                 KOption::Double => {
-                    let value = unpack_number!(reader_state, buffer, f64);
+                    let value = unpack_number!(field, buffer, f64);
                     lua::pushnumber(state, value);
                 }
-                KOption::Char => {
+                KOption::Varint | KOption::ZigZag => {
+                    let offset = buffer.seek(std::io::SeekFrom::Current(0))? as usize;
+                    let (value, len) = match read_varint(&data[offset..]) {
+                        Some(result) => result,
+                        None => {
+                            return Err(StructError::Error(lua::cstr!("data string too short")))
+                        }
+                    };
+                    buffer.seek(std::io::SeekFrom::Current(len as _))?;
+                    match field.option {
+                        KOption::Varint => lua::pushnumber(state, value as _),
+                        _ => lua::pushnumber(state, zigzag_decode(value) as _),
+                    }
+                }
+                KOption::Bool => {
+                    let value = unpack_number!(field, buffer, u8);
+                    lua::pushboolean(state, (value != 0) as _);
+                }
+                KOption::Vector | KOption::Angle => {
+                    let mut value = [0.0; 3];
+                    for component in value.iter_mut() {
+                        *component = if field.size == 3 * std::mem::size_of::<f32>() {
+                            unpack_number!(field, buffer, f32) as f64
+                        } else {
+                            unpack_number!(field, buffer, f64)
+                        };
+                    }
+                    match field.option {
+                        KOption::Vector => gmod::push_vector(state, value),
+                        _ => gmod::push_angle(state, value),
+                    }
+                }
+                KOption::Color => {
+                    let mut value = [0; 4];
+                    buffer.read(&mut value)?;
+                    gmod::push_color(state, value);
+                }
+                KOption::Decimal(scale) => {
+                    let value = unpack_number!(field, buffer, i64);
+                    decimal::push_decimal(state, Decimal::new(value, scale));
+                }
+                KOption::Array(ref group) => {
+                    let count = unpack_number!(field, buffer, u16);
+                    lua::createtable(state, count as _, 0);
+                    let array = lua::gettop(state);
+                    for i in 1..=count as i32 {
+                        lua::createtable(state, group.len() as _, 0);
+                        unpack_into(state, group, buffer, Some(array + 1))?;
+                        lua::rawseti(state, array, i);
+                    }
+                }
+                KOption::Char | KOption::CharExact => {
                     let offset = buffer.seek(std::io::SeekFrom::Current(0))? as usize;
-                    lua::pushlstring(state, data.as_ptr().add(offset), size);
+                    buffer.seek(std::io::SeekFrom::Current(field.size as _))?;
+                    lua::pushlstring(state, data.as_ptr().add(offset), field.size);
                 }
                 KOption::String => {
-                    let value = unpack_number!(reader_state, buffer, u16);
+                    let value = unpack_number!(field, buffer, u16);
                     let offset = buffer.seek(std::io::SeekFrom::Current(0))? as usize;
                     if offset + value as usize > data.len() {
                         return Err(StructError::Error(lua::cstr!("data string too short")));
@@ -291,9 +1005,749 @@ pub fn unpack(state: lua_State, fmt: &[u8], data: &[u8]) -> Result<i32, StructEr
                     buffer.seek(std::io::SeekFrom::Current(value as _))?;
                     lua::pushlstring(state, data.as_ptr().add(offset), value as _);
                 }
-                KOption::NOP => {}
+                KOption::Int(signed) => {
+                    lua::pushnumber(state, unpack_number(field, signed, buffer)?);
+                }
+                KOption::SizedString => {
+                    let len = unpack_int(field, false, buffer)? as u64 as usize;
+                    let offset = buffer.seek(std::io::SeekFrom::Current(0))? as usize;
+                    if len > data.len() - offset {
+                        return Err(StructError::Error(lua::cstr!("data string too short")));
+                    }
+                    buffer.seek(std::io::SeekFrom::Current(len as _))?;
+                    lua::pushlstring(state, data.as_ptr().add(offset), len);
+                }
+                KOption::ZString => {
+                    let offset = buffer.seek(std::io::SeekFrom::Current(0))? as usize;
+                    let len = match data[offset..].iter().position(|byte| *byte == 0) {
+                        Some(len) => len,
+                        None => {
+                            return Err(StructError::Error(lua::cstr!(
+                                "unfinished string for format 'z'"
+                            )))
+                        }
+                    };
+                    buffer.seek(std::io::SeekFrom::Current(len as i64 + 1))?;
+                    lua::pushlstring(state, data.as_ptr().add(offset), len);
+                }
+                KOption::Padding | KOption::Align | KOption::NOP => {}
+            }
+            if let Some(table) = table {
+                lua::rawseti(state, table, nrets);
             }
         }
         Ok(nrets)
     }
 }
+
+/// Returns why `data` doesn't decode cleanly with `fmt`, if it doesn't.
+/// Only sizes and integer ranges are checked, no lua values are created.
+pub fn validate(fmt: &Struct, data: &[u8]) -> Option<String> {
+    let mut buffer = Cursor::new(data);
+    match skip(fmt, &mut buffer) {
+        Ok(()) if (buffer.position() as usize) < data.len() => Some(format!(
+            "{} trailing bytes",
+            data.len() - buffer.position() as usize
+        )),
+        Ok(()) => None,
+        Err(e) => Some(e.to_string()),
+    }
+}
+
+/// Moves `buffer` past values packed with `fmt`, failing where `unpack_into` would.
+fn skip(fmt: &Struct, buffer: &mut Cursor<&[u8]>) -> Result<(), StructError> {
+    let data: &[u8] = buffer.get_ref();
+    let too_short = || StructError::Error(lua::cstr!("data string too short"));
+    let bitmap = buffer.position() as usize;
+    let bitmap_size = fmt.bitmap_size();
+    if data.len() - bitmap < bitmap_size {
+        return Err(too_short());
+    }
+    buffer.set_position((bitmap + bitmap_size) as u64);
+    let mut bit = 0;
+    for field in fmt.0.iter() {
+        if field.optional {
+            let present = data[bitmap + bit / 8] & (1 << (bit % 8)) != 0;
+            bit += 1;
+            if !present {
+                continue;
+            }
+        }
+        let mut offset = buffer.position() as usize;
+        if field.align > 0 {
+            offset += (field.align - offset % field.align) % field.align;
+        }
+        if offset > data.len() || data.len() - offset < field.size {
+            return Err(too_short());
+        }
+        buffer.set_position(offset as u64);
+        // Bytes left to skip after the length prefix, if there is one.
+        let len = match field.option {
+            KOption::Varint | KOption::ZigZag => match read_varint(&data[offset..]) {
+                Some((_, len)) => len,
+                None => return Err(too_short()),
+            },
+            KOption::Array(ref group) => {
+                for _ in 0..unpack_number!(field, buffer, u16) {
+                    skip(group, buffer)?;
+                }
+                0
+            }
+            KOption::String => unpack_number!(field, buffer, u16) as usize,
+            KOption::Int(signed) => {
+                unpack_int(field, signed, buffer)?;
+                0
+            }
+            KOption::SizedString => unpack_int(field, false, buffer)? as u64 as usize,
+            KOption::ZString => match data[offset..].iter().position(|byte| *byte == 0) {
+                Some(len) => len + 1,
+                None => {
+                    return Err(StructError::Error(lua::cstr!(
+                        "unfinished string for format 'z'"
+                    )))
+                }
+            },
+            _ => field.size,
+        };
+        let offset = buffer.position() as usize;
+        if len > data.len() - offset {
+            return Err(too_short());
+        }
+        buffer.set_position((offset + len) as u64);
+    }
+    Ok(())
+}
+
+impl KOption {
+    fn name(&self) -> &'static str {
+        match self {
+            KOption::I8 => "i8",
+            KOption::U8 => "u8",
+            KOption::I16 => "i16",
+            KOption::U16 => "u16",
+            KOption::I32 => "i32",
+            KOption::U32 => "u32",
+            KOption::Usize => "usize",
+            KOption::Float => "float",
+            KOption::Double => "double",
+            KOption::Varint => "varint",
+            KOption::ZigZag => "zigzag",
+            KOption::Bool => "bool",
+            KOption::Vector => "vector",
+            KOption::Angle => "angle",
+            KOption::Color => "color",
+            KOption::Array(_) => "array",
+            KOption::Decimal(_) => "decimal",
+            KOption::Char | KOption::CharExact => "char",
+            KOption::String | KOption::SizedString => "string",
+            KOption::Int(true) => "int",
+            KOption::Int(false) => "uint",
+            KOption::ZString => "zstring",
+            KOption::Padding => "padding",
+            KOption::Align => "align",
+            KOption::NOP => "nop",
+        }
+    }
+}
+
+impl Struct {
+    /// Pushes table describing every field, see `sled.DescribeFormat`.
+    unsafe fn describe(&self, state: lua_State) {
+        lua::createtable(state, self.0.len() as _, 2);
+        let mut offset = Some(self.bitmap_size());
+        for (i, field) in self.0.iter().enumerate() {
+            if field.align > 0 {
+                offset =
+                    offset.map(|offset| (offset + field.align - 1) / field.align * field.align);
+            }
+            lua::createtable(state, 0, 6);
+            let kind = field.option.name();
+            lua::pushlstring(state, kind.as_ptr(), kind.len());
+            lua::setfield(state, -2, lua::cstr!("kind"));
+            lua::pushinteger(state, field.size as _);
+            lua::setfield(state, -2, lua::cstr!("size"));
+            let endianness = match field.endianness {
+                Endianness::Little => "little",
+                Endianness::Big => "big",
+                Endianness::Native => "native",
+            };
+            lua::pushlstring(state, endianness.as_ptr(), endianness.len());
+            lua::setfield(state, -2, lua::cstr!("endianness"));
+            if let Some(offset) = offset {
+                lua::pushinteger(state, offset as _);
+                lua::setfield(state, -2, lua::cstr!("offset"));
+            }
+            lua::pushboolean(state, field.optional as _);
+            lua::setfield(state, -2, lua::cstr!("optional"));
+            match field.option {
+                KOption::Array(ref group) => {
+                    group.describe(state);
+                    lua::setfield(state, -2, lua::cstr!("fields"));
+                }
+                KOption::Decimal(scale) => {
+                    lua::pushinteger(state, scale as _);
+                    lua::setfield(state, -2, lua::cstr!("scale"));
+                }
+                _ => {}
+            }
+            lua::rawseti(state, -2, i as i32 + 1);
+            offset = match field.option {
+                _ if field.optional => None,
+                KOption::String
+                | KOption::SizedString
+                | KOption::ZString
+                | KOption::Varint
+                | KOption::ZigZag
+                | KOption::Array(_) => None,
+                _ => offset.map(|offset| offset + field.size),
+            };
+        }
+        let (size, fixed) = self.size();
+        lua::pushinteger(state, size as _);
+        lua::setfield(state, -2, lua::cstr!("size"));
+        lua::pushboolean(state, fixed as _);
+        lua::setfield(state, -2, lua::cstr!("fixed"));
+    }
+
+    /// Size of all fixed-size fields and whether whole struct has fixed size.
+    fn size(&self) -> (usize, bool) {
+        self.0
+            .iter()
+            .fold((self.bitmap_size(), true), |(size, fixed), field| {
+                let size = match field.align {
+                    0 => size,
+                    align => (size + align - 1) / align * align,
+                };
+                match field.option {
+                    _ if field.optional => (size, false),
+                    KOption::String
+                    | KOption::SizedString
+                    | KOption::ZString
+                    | KOption::Varint
+                    | KOption::ZigZag
+                    | KOption::Array(_) => (size + field.size, false),
+                    _ => (size + field.size, fixed),
+                }
+            })
+    }
+
+    /// Size of presence bitmap of optional fields.
+    fn bitmap_size(&self) -> usize {
+        (self.0.iter().filter(|field| field.optional).count() + 7) / 8
+    }
+
+    /// Number of values this struct packs and unpacks.
+    pub fn len(&self) -> usize {
+        self.0.iter().filter(|field| field.has_value()).count()
+    }
+
+    // Field of the value at `index`, skipping padding.
+    fn value_field(&self, index: usize) -> &Field {
+        self.0
+            .iter()
+            .filter(|field| field.has_value())
+            .nth(index)
+            .expect("value index out of range")
+    }
+
+    pub fn append(&mut self, other: Struct) {
+        self.0.extend(other.0);
+    }
+
+    /// Whether option at `index` accepts lua value of type `typ`.
+    pub fn accepts(&self, index: usize, typ: i32) -> bool {
+        match self.value_field(index).option {
+            KOption::Bool => typ == 1,
+            KOption::Char
+            | KOption::CharExact
+            | KOption::String
+            | KOption::SizedString
+            | KOption::ZString => typ == 4,
+            KOption::Color | KOption::Array(_) => typ == 5,
+            KOption::Vector | KOption::Angle => typ == 7,
+            KOption::Decimal(_) => typ == 3 || typ == 4 || typ == 7,
+            _ => typ == 3,
+        }
+    }
+
+    /// Name of the value expected by option at `index`, for error messages.
+    pub fn type_name(&self, index: usize) -> &'static str {
+        match self.value_field(index).option {
+            KOption::Bool => "boolean",
+            KOption::Char
+            | KOption::CharExact
+            | KOption::String
+            | KOption::SizedString
+            | KOption::ZString => "string",
+            KOption::Vector => "Vector",
+            KOption::Angle => "Angle",
+            KOption::Color => "Color",
+            KOption::Array(_) => "table",
+            KOption::Decimal(_) => "decimal",
+            _ => "number",
+        }
+    }
+
+    /// Pushes zero value for option at `index`.
+    pub unsafe fn push_default(&self, state: lua_State, index: usize) {
+        match self.value_field(index).option {
+            KOption::Bool => lua::pushboolean(state, 0 as _),
+            KOption::Char
+            | KOption::CharExact
+            | KOption::String
+            | KOption::SizedString
+            | KOption::ZString => lua::pushlstring(state, "".as_ptr(), 0),
+            KOption::Vector => gmod::push_vector(state, [0.0; 3]),
+            KOption::Angle => gmod::push_angle(state, [0.0; 3]),
+            KOption::Color => gmod::push_color(state, [255; 4]),
+            KOption::Array(_) => lua::createtable(state, 0, 0),
+            KOption::Decimal(scale) => decimal::push_decimal(state, Decimal::new(0, scale)),
+            _ => lua::pushinteger(state, 0),
+        }
+    }
+
+    pub fn is_optional(&self, index: usize) -> bool {
+        self.value_field(index).optional
+    }
+
+    pub fn l_new(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let fmt = match compile(check_slice!(state, 1)) {
+                Ok(fmt) => fmt,
+                Err(e) => return Err(e.into()),
+            };
+            let udata = lua::newuserdata(state, std::mem::size_of::<Self>()).cast::<Self>();
+            udata.write(fmt);
+            Self::metatable(state);
+            lua::setmetatable(state, -2);
+        }
+        Ok(1)
+    }
+
+    fn lm_pack(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &*lua::Lcheckudata(state, 1, lua::cstr!("csls")).cast::<Self>();
+            match pack(state, this, 2) {
+                Ok(data) => {
+                    lua::pushlstring(state, data.as_ptr(), data.len());
+                    Ok(1)
+                }
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+
+    /// `Unpack(data)`, Lua 5.3 formats also take `init` position
+    /// and return position of the first unread byte after the values.
+    fn lm_unpack(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &*lua::Lcheckudata(state, 1, lua::cstr!("csls")).cast::<Self>();
+            let data = check_slice!(state, 2);
+            if !this.1 {
+                return match unpack(state, this, data) {
+                    Ok(args) => Ok(args),
+                    Err(e) => Err(e.into()),
+                };
+            }
+            let pos = match lua::Loptinteger(state, 3, 1) as i64 {
+                init if init < 0 => data.len() as i64 + init,
+                init => init - 1,
+            };
+            if pos < 0 || pos as usize > data.len() {
+                lua::Largerror(state, 3, lua::cstr!("initial position out of string"));
+            }
+            match unpack_at(state, this, data, pos as usize) {
+                Ok((args, pos)) => {
+                    lua::pushinteger(state, (pos + 1) as _);
+                    Ok(args + 1)
+                }
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+
+    fn lm_unpack_table(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &*lua::Lcheckudata(state, 1, lua::cstr!("csls")).cast::<Self>();
+            let data = check_slice!(state, 2);
+            match unpack_table(state, this, data) {
+                Ok(args) => Ok(args),
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+
+    /// `sled.UnpackTable(fmt, data)`
+    pub fn l_unpack_table(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let fmt = match check_struct(state, 1) {
+                Ok(fmt) => fmt,
+                Err(e) => return Err(e.into()),
+            };
+            let data = check_slice!(state, 2);
+            match unpack_table(state, &fmt, data) {
+                Ok(args) => Ok(args),
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+
+    fn lm_size(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &*lua::Lcheckudata(state, 1, lua::cstr!("csls")).cast::<Self>();
+            let (size, fixed) = this.size();
+            lua::pushinteger(state, size as _);
+            lua::pushboolean(state, fixed as _);
+            Ok(2)
+        }
+    }
+
+    /// `sled.DescribeFormat(fmt)`
+    pub fn l_describe(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let fmt = match check_struct(state, 1) {
+                Ok(fmt) => fmt,
+                Err(e) => return Err(e.into()),
+            };
+            fmt.describe(state);
+            Ok(1)
+        }
+    }
+
+    fn __gc(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            lua::Lcheckudata(state, 1, lua::cstr!("csls"))
+                .cast::<Self>()
+                .drop_in_place();
+            Ok(0)
+        }
+    }
+
+    pub unsafe fn metatable(state: lua_State) {
+        if lua::Lnewmetatable(state, lua::cstr!("csls")) {
+            lua::pushvalue(state, -1);
+            lua::setfield(state, -2, lua::cstr!("__index"));
+            insert_function!(state, "__gc", Self::__gc);
+            insert_function!(state, "Pack", Self::lm_pack);
+            insert_function!(state, "Unpack", Self::lm_unpack);
+            insert_function!(state, "UnpackTable", Self::lm_unpack_table);
+            insert_function!(state, "Size", Self::lm_size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(fmt: &[u8]) -> (Field, bool) {
+        let mut fmt = compile(fmt).unwrap();
+        assert_eq!(fmt.0.len(), 1);
+        let field = fmt.0.remove(0);
+        match field.option {
+            KOption::Int(signed) => (field, signed),
+            _ => panic!("integer option expected"),
+        }
+    }
+
+    fn pack_lua53(fmt: &[u8], value: f64) -> Result<Vec<u8>, String> {
+        let (field, signed) = field(fmt);
+        let value = check_int(value, &field, signed, 1).map_err(|e| e.to_string())?;
+        let mut data = [0; MAX_INT_SIZE];
+        let mut buffer = Cursor::new(&mut data[..]);
+        pack_int(value, &field, signed, &mut buffer).unwrap();
+        let len = buffer.position() as usize;
+        Ok(data[..len].to_vec())
+    }
+
+    fn unpack_lua53(fmt: &[u8], data: &[u8]) -> Result<i64, String> {
+        let (field, signed) = field(fmt);
+        let mut buffer = Cursor::new(data);
+        unpack_int(&field, signed, &mut buffer).map_err(|e| e.to_string())
+    }
+
+    // Expected bytes are what Lua 5.3 `string.pack` produces for the same arguments.
+    #[test]
+    fn lua53_pack_integers() {
+        let mut big_one = vec![0; 16];
+        big_one[15] = 1;
+        let mut i9 = vec![0xFE];
+        i9.extend([0xFF; 8]);
+        let mut i10 = vec![0xFF; 8];
+        i10.extend([0, 0]);
+        let cases: &[(&[u8], f64, Vec<u8>)] = &[
+            (b"#<b", -1.0, vec![0xFF]),
+            (b"#<B", 255.0, vec![0xFF]),
+            (b"#<h", -32768.0, vec![0x00, 0x80]),
+            (b"#>I2", 258.0, vec![0x01, 0x02]),
+            (b"#<i3", -2.0, vec![0xFE, 0xFF, 0xFF]),
+            (b"#>i4", -2.0, vec![0xFF, 0xFF, 0xFF, 0xFE]),
+            (b"#<I3", 65536.0, vec![0x00, 0x00, 0x01]),
+            (b"#<j", 9007199254740992.0, vec![0, 0, 0, 0, 0, 0, 0x20, 0]),
+            (
+                b"#<j",
+                -9223372036854775808.0,
+                vec![0, 0, 0, 0, 0, 0, 0, 0x80],
+            ),
+            (b"#<J", -1.0, vec![0xFF; 8]),
+            (b"#<i9", -2.0, i9),
+            (b"#<I10", -1.0, i10),
+            (b"#<i16", -1.0, vec![0xFF; 16]),
+            (b"#>i16", 1.0, big_one),
+        ];
+        for (fmt, value, expected) in cases {
+            assert_eq!(
+                pack_lua53(fmt, *value).as_ref(),
+                Ok(expected),
+                "string.pack({:?}, {})",
+                String::from_utf8_lossy(fmt),
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn lua53_pack_integer_errors() {
+        let cases: &[(&[u8], f64, &str)] = &[
+            (b"#<b", 128.0, "integer overflow"),
+            (b"#<b", -129.0, "integer overflow"),
+            (b"#<i3", 8388608.0, "integer overflow"),
+            (b"#<B", 256.0, "unsigned overflow"),
+            (b"#<B", -1.0, "unsigned overflow"),
+            (b"#<j", 9223372036854775808.0, "integer overflow"),
+            (b"#<J", 18446744073709551616.0, "integer overflow"),
+            (b"#<i16", -1.8446744073709552e19, "integer overflow"),
+            (b"#<i4", 1.5, "number has no integer representation"),
+            (
+                b"#<i4",
+                f64::INFINITY,
+                "number has no integer representation",
+            ),
+            (b"#<i4", f64::NAN, "number has no integer representation"),
+        ];
+        for (fmt, value, expected) in cases {
+            assert_eq!(
+                pack_lua53(fmt, *value),
+                Err(format!("bad argument #1 ({})", expected)),
+                "string.pack({:?}, {})",
+                String::from_utf8_lossy(fmt),
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn lua53_unpack_integers() {
+        let mut i16 = vec![0xFE];
+        i16.extend([0xFF; 15]);
+        let mut u9 = vec![0; 9];
+        u9[0] = 1;
+        let cases: &[(&[u8], Vec<u8>, i64)] = &[
+            (b"#<b", vec![0xFF], -1),
+            (b"#<B", vec![0xFF], 255),
+            (b"#>i2", vec![0xFF, 0xFE], -2),
+            (b"#<i3", vec![0xFE, 0xFF, 0xFF], -2),
+            (b"#<I3", vec![0xFE, 0xFF, 0xFF], 0xFFFFFE),
+            (b"#<i9", vec![0xFF; 9], -1),
+            (b"#<I9", u9, 1),
+            (b"#<i16", i16, -2),
+        ];
+        for (fmt, data, expected) in cases {
+            assert_eq!(
+                unpack_lua53(fmt, data),
+                Ok(*expected),
+                "string.unpack({:?}, {:?})",
+                String::from_utf8_lossy(fmt),
+                data
+            );
+        }
+        let mut i9 = vec![0; 9];
+        i9[8] = 1;
+        assert!(unpack_lua53(b"#<i9", &i9).is_err());
+        assert!(unpack_lua53(b"#<I9", &[0xFF; 9]).is_err());
+        assert!(unpack_lua53(b"#<i4", &[0xFF; 3]).is_err());
+    }
+
+    // Expected numbers are what Lua 5.3 `string.unpack` returns.
+    #[test]
+    fn lua53_unpack_numbers() {
+        let cases: &[(&[u8], &[u8], f64)] = &[
+            (b"#<J", &[0xFF; 8], -1.0),
+            (b"#<T", &[0xFF; 8], -1.0),
+            (b"#<I8", &[0xFF; 8], -1.0),
+            (b"#>I8", &[0x80, 0, 0, 0, 0, 0, 0, 0], i64::MIN as f64),
+            (b"#<I4", &[0xFF; 4], 4294967295.0),
+            (b"#<i4", &[0xFF; 4], -1.0),
+            (
+                b"#<I16",
+                &[
+                    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0, 0, 0, 0, 0,
+                ],
+                -1.0,
+            ),
+        ];
+        for (fmt, data, expected) in cases {
+            let (field, signed) = field(fmt);
+            let mut buffer = Cursor::new(*data);
+            let value = unpack_number(&field, signed, &mut buffer).unwrap();
+            assert_eq!(value, *expected, "{}", String::from_utf8_lossy(fmt));
+        }
+        for fmt in [&b"#<J"[..], b"#>T", b"#<I8", b"#<j"] {
+            let data = pack_lua53(fmt, -1.0).unwrap();
+            let (field, signed) = field(fmt);
+            let mut buffer = Cursor::new(&data[..]);
+            assert_eq!(unpack_number(&field, signed, &mut buffer).unwrap(), -1.0);
+        }
+    }
+
+    // Expected sizes are what Lua 5.3 `string.packsize` returns.
+    #[test]
+    fn lua53_packsize() {
+        let cases: &[(&[u8], usize)] = &[
+            (b"#i4", 4),
+            (b"#b d", 9),
+            (b"#!4 b i4", 8),
+            (b"#!8 b d", 16),
+            (b"#!2 b i8", 10),
+            (b"#!16 b i16", 32),
+            (b"#! b j", 16),
+            (b"#! b f", 8),
+            (b"#Xx", 0),
+            (b"#bXxb", 2),
+            (b"#!4 b Xi4 b", 5),
+            (b"#!4 b Xh", 2),
+            (b"#c3 x i2", 6),
+            (b"#!4 c1 x i2", 4),
+        ];
+        for (fmt, expected) in cases {
+            let size = compile(fmt)
+                .map(|fmt| fmt.size().0)
+                .map_err(|e| e.to_string());
+            assert_eq!(
+                size,
+                Ok(*expected),
+                "string.packsize({:?})",
+                String::from_utf8_lossy(fmt)
+            );
+        }
+    }
+
+    #[test]
+    fn lua53_invalid_formats() {
+        let cases: &[&[u8]] = &[
+            b"#X", b"#Xc1", b"#Xz", b"#X<", b"#c", b"#i0", b"#i17", b"#!17", b"#!3 i4", b"#! i3",
+            b"#y",
+        ];
+        for fmt in cases {
+            assert!(compile(fmt).is_err(), "{:?}", String::from_utf8_lossy(fmt));
+        }
+    }
+
+    #[test]
+    fn validate_sizes() {
+        let fmt = compile(b"<H c2 s").unwrap();
+        assert_eq!(validate(&fmt, b"\x01\x00ab\x02\x00xy"), None);
+        assert_eq!(
+            validate(&fmt, b"\x01\x00ab\x02\x00xyz").as_deref(),
+            Some("1 trailing bytes")
+        );
+        assert_eq!(
+            validate(&fmt, b"\x01\x00ab\x03\x00xy").as_deref(),
+            Some("data string too short")
+        );
+        assert_eq!(
+            validate(&fmt, b"\x01").as_deref(),
+            Some("data string too short")
+        );
+    }
+
+    #[test]
+    fn validate_optional_varint_and_arrays() {
+        let fmt = compile(b"<^u *(H) vaC").unwrap();
+        let mut data = vec![0x00, 0x02, 0x00, 0x01, 0x00, 0x02, 0x00];
+        data.extend([0; 28]);
+        assert_eq!(validate(&fmt, &data), None);
+        data[0] = 0x01;
+        assert!(validate(&fmt, &data).is_some());
+        data.insert(1, 0x7F);
+        assert_eq!(validate(&fmt, &data), None);
+        data[1] = 0xFF;
+        assert!(validate(&fmt, &data).is_some());
+    }
+
+    #[test]
+    fn validate_lua53() {
+        let fmt = compile(b"#<!4 b i4 z").unwrap();
+        assert_eq!(validate(&fmt, b"\x01\0\0\0\x02\0\0\0hi\0"), None);
+        assert_eq!(
+            validate(&fmt, b"\x01\0\0\0\x02\0\0\0hi").as_deref(),
+            Some("unfinished string for format 'z'")
+        );
+        let fmt = compile(b"#<i9").unwrap();
+        assert!(validate(&fmt, &[0, 0, 0, 0, 0, 0, 0, 0, 1]).is_some());
+    }
+
+    #[test]
+    fn padding_up_to_max_alignment() {
+        let mut data = [0xAA; 32];
+        let mut buffer = Cursor::new(&mut data[..]);
+        buffer.write_all(&[1]).unwrap();
+        write_padding(&mut buffer, 16).unwrap();
+        assert_eq!(buffer.position(), 16);
+        write_padding(&mut buffer, 16).unwrap();
+        assert_eq!(buffer.position(), 16);
+        assert!(data[1..16].iter().all(|byte| *byte == 0));
+        assert_eq!(data[16], 0xAA);
+    }
+
+    #[test]
+    fn padding_overflow() {
+        let mut data = [0; 10];
+        let mut buffer = Cursor::new(&mut data[..]);
+        buffer.write_all(&[1]).unwrap();
+        assert!(write_padding(&mut buffer, 16).is_err());
+    }
+
+    #[test]
+    fn varint_roundtrip() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX - 1, u64::MAX] {
+            let mut out = [0; MAX_VARINT_SIZE];
+            let encoded = write_varint(value, &mut out).to_vec();
+            assert_eq!(read_varint(&encoded), Some((value, encoded.len())));
+        }
+    }
+
+    #[test]
+    fn varint_known_bytes() {
+        let mut out = [0; MAX_VARINT_SIZE];
+        assert_eq!(write_varint(300, &mut out), &[0xAC, 0x02]);
+        assert_eq!(
+            write_varint(u64::MAX, &mut out),
+            &[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01]
+        );
+        assert_eq!(read_varint(&[0x96, 0x01, 0xFF]), Some((150, 2)));
+    }
+
+    #[test]
+    fn varint_rejects_overflow_and_truncation() {
+        let mut data = [0xFF; MAX_VARINT_SIZE];
+        data[9] = 0x02;
+        assert_eq!(read_varint(&data), None);
+        data[9] = 0x7F;
+        assert_eq!(read_varint(&data), None);
+        assert_eq!(read_varint(&[0xFF; 11]), None);
+        assert_eq!(read_varint(&[0x80, 0x80]), None);
+        assert_eq!(read_varint(&[]), None);
+    }
+
+    #[test]
+    fn zigzag_roundtrip() {
+        assert_eq!(zigzag_encode(0), 0);
+        assert_eq!(zigzag_encode(-1), 1);
+        assert_eq!(zigzag_encode(1), 2);
+        assert_eq!(zigzag_encode(i64::MIN), u64::MAX);
+        for value in [0, 1, -1, 63, -64, i64::MAX, i64::MIN] {
+            assert_eq!(zigzag_decode(zigzag_encode(value)), value);
+        }
+    }
+}
@@ -7,6 +7,16 @@ macro_rules! check_slice {
     }};
 }
 
+#[macro_export]
+macro_rules! struct_try {
+    ($state:ident, $expr:expr) => {
+        match $expr {
+            Ok(result) => result,
+            Err(e) => return Err(e.into()),
+        }
+    };
+}
+
 #[macro_export]
 macro_rules! insert_function {
     ($state:ident, $name:expr, $func:expr) => {
@@ -25,6 +35,7 @@ macro_rules! tree_get_key {
                     let key = check_slice!(state, 2);
                     let lt = this.$name(key)?;
                     if let Some((key, value)) = lt {
+                        let value = this.decode_value(&key, value)?;
                         lua::pushlstring(state, key.as_ptr(), key.len());
                         lua::pushlstring(state, value.as_ptr(), value.len());
                         Ok(2)
@@ -38,25 +49,14 @@ macro_rules! tree_get_key {
                 unsafe {
                     let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("csldb")).cast::<Self>();
                     let key = check_slice!(state, 2);
-                    let fmt = check_slice!(state, 3);
-                    if let Some((key, value)) = this.$name(key)? {
+                    let fmt = struct_try!(state, lua_struct::check_struct(state, 3));
+                    let result = if let Some((key, value)) = this.$name(key)? {
                         lua::pushlstring(state, key.as_ptr(), key.len());
-                        match lua_struct::unpack(state, fmt, &value) {
-                            Ok(args) => Ok(args + 1),
-                            Err(e) => {
-                                drop(key);
-                                drop(value);
-                                match e {
-                                    StructError::Error(e) => lua::Lerror(state, e),
-                                    StructError::ArgError(arg, e) => lua::Largerror(state, arg, e),
-                                    StructError::InvalidFormatOption(e, opt) => lua::Lerror(state, e, opt),
-                                    StructError::IOError(e) => return Err(e)?,
-                                }
-                            }
-                        }
+                        lua_struct::unpack(state, &fmt, &this.decode_value(&key, value)?)
                     } else {
-                        Ok(0)
-                    }
+                        return Ok(0)
+                    };
+                    Ok(struct_try!(state, result) + 1)
                 }
             }
         }
@@ -74,6 +74,7 @@ macro_rules! tree_get_no_arg {
                 unsafe {
                     let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!($udata)).cast::<Self>();
                     if let Some((key, value)) = this.$name()? {
+                        let value = this.decode_value(&key, value)?;
                         lua::pushlstring(state, key.as_ptr(), key.len());
                         lua::pushlstring(state, value.as_ptr(), value.len());
                         Ok(2)
@@ -86,30 +87,57 @@ macro_rules! tree_get_no_arg {
             fn [<lm_ $name _struct>](state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
                 unsafe {
                     let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!($udata)).cast::<Self>();
-                    let fmt = check_slice!(state, 2);
-                    if let Some((key, value)) = this.$name()? {
+                    let fmt = struct_try!(state, lua_struct::check_struct(state, 2));
+                    let result = if let Some((key, value)) = this.$name()? {
+                        lua::pushlstring(state, key.as_ptr(), key.len());
+                        lua_struct::unpack(state, &fmt, &this.decode_value(&key, value)?)
+                    } else {
+                        return Ok(0)
+                    };
+                    Ok(struct_try!(state, result) + 1)
+                }
+            }
+        }
+    };
+    ($($name:ident )+, $udata:expr) => {
+        $(tree_get_no_arg!($name, $udata);)+
+    };
+}
+
+/// Pops through the codec, so entries that can't be decoded stay in the tree.
+#[macro_export]
+macro_rules! tree_pop {
+    ($name:ident, $udata:expr) => {
+        paste::paste! {
+            fn [<lm_ $name>](state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+                unsafe {
+                    let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!($udata)).cast::<Self>();
+                    if let Some((key, value)) = this.1.get().$name(&this.0)? {
                         lua::pushlstring(state, key.as_ptr(), key.len());
-                        match lua_struct::unpack(state, fmt, &value) {
-                            Ok(args) => Ok(args + 1),
-                            Err(e) => {
-                                drop(key);
-                                drop(value);
-                                match e {
-                                    StructError::Error(e) => lua::Lerror(state, e),
-                                    StructError::ArgError(arg, e) => lua::Largerror(state, arg, e),
-                                    StructError::InvalidFormatOption(e, opt) => lua::Lerror(state, e, opt),
-                                    StructError::IOError(e) => return Err(e)?,
-                                }
-                            }
-                        }
+                        lua::pushlstring(state, value.as_ptr(), value.len());
+                        Ok(2)
                     } else {
                         Ok(0)
                     }
                 }
             }
+
+            fn [<lm_ $name _struct>](state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+                unsafe {
+                    let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!($udata)).cast::<Self>();
+                    let fmt = struct_try!(state, lua_struct::check_struct(state, 2));
+                    let result = if let Some((key, value)) = this.1.get().$name(&this.0)? {
+                        lua::pushlstring(state, key.as_ptr(), key.len());
+                        lua_struct::unpack(state, &fmt, &value)
+                    } else {
+                        return Ok(0)
+                    };
+                    Ok(struct_try!(state, result) + 1)
+                }
+            }
         }
     };
     ($($name:ident )+, $udata:expr) => {
-        $(tree_get_no_arg!($name, $udata);)+
+        $(tree_pop!($name, $udata);)+
     };
 }
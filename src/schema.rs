@@ -0,0 +1,525 @@
+use std::borrow::Cow;
+use std::ffi::CString;
+
+use lua_shared as lua;
+use lua_shared::lua_State;
+
+use crate::codec::Codec;
+use crate::lua_struct::{self, Struct};
+use crate::{check_slice, insert_function, struct_try};
+
+struct SchemaField {
+    name: CString,
+    // Amount of format options this field consists of.
+    // Fields with more than one option are represented as arrays in lua.
+    count: usize,
+}
+
+struct Migration {
+    from: u16,
+    // Registry references to the schema records were stored with
+    // and to the function that turns them into current ones.
+    schema: i32,
+    func: i32,
+}
+
+/// Named set of struct fields, packed from and unpacked to lua tables.
+/// Exposed to lua as `sled.Schema({ {"name", "s"}, {"pos", "ddd"} }[, version])`.
+///
+/// Records of versioned schema start with `VERSION_MAGIC` followed by u16 version.
+/// Header is only recognized when it names the current version, a version with
+/// a registered migration or a newer one. Anything else is treated as version 0.
+pub struct Schema {
+    fields: Vec<SchemaField>,
+    fmt: Struct,
+    version: u16,
+    migrations: Vec<Migration>,
+}
+
+const VERSION_MAGIC: [u8; 4] = [0xFE, b's', b'v', 0x01];
+const HEADER_SIZE: usize = VERSION_MAGIC.len() + 2;
+
+unsafe fn to_slice<'a>(state: lua_State, index: i32) -> &'a [u8] {
+    let mut len = 0;
+    let str_ptr = lua::tolstring(state, index, &mut len);
+    std::slice::from_raw_parts(str_ptr, len)
+}
+
+impl Schema {
+    /// Replaces nil on top of the stack with default value
+    /// and makes sure that anything else has right type.
+    unsafe fn check_value(
+        &self,
+        state: lua_State,
+        field: &SchemaField,
+        option: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match lua::get_type(state, -1) {
+            0 if self.fmt.is_optional(option) => {}
+            0 => {
+                lua::settop(state, -2);
+                self.fmt.push_default(state, option);
+            }
+            typ if self.fmt.accepts(option, typ) => {}
+            _ => {
+                return Err(format!(
+                    "field '{}': {} expected",
+                    field.name.to_string_lossy(),
+                    self.fmt.type_name(option)
+                )
+                .into())
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns `sled.Schema` at `index` if there is one.
+    pub unsafe fn test<'a>(state: lua_State, index: i32) -> Option<&'a Self> {
+        if crate::test_metatable(state, index, lua::cstr!("cslsc")) {
+            Some(&*lua::touserdata(state, index).cast::<Self>())
+        } else {
+            None
+        }
+    }
+
+    fn split_header<'a>(&self, data: &'a [u8]) -> (u16, &'a [u8]) {
+        if self.version == 0 || data.len() < HEADER_SIZE || data[..4] != VERSION_MAGIC {
+            return (0, data);
+        }
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        let known = version >= self.version || self.migrations.iter().any(|m| m.from == version);
+        if version == 0 || !known {
+            return (0, data);
+        }
+        (version, &data[HEADER_SIZE..])
+    }
+
+    /// Packs table at absolute stack `index`, prepending version header if needed.
+    pub unsafe fn pack(
+        &self,
+        state: lua_State,
+        index: i32,
+    ) -> Result<Cow<'static, [u8]>, Box<dyn std::error::Error>> {
+        let data = self.pack_payload(state, index)?;
+        if self.version == 0 {
+            return Ok(Cow::Borrowed(data));
+        }
+        let mut result = Vec::with_capacity(HEADER_SIZE + data.len());
+        result.extend_from_slice(&VERSION_MAGIC);
+        result.extend_from_slice(&self.version.to_le_bytes());
+        result.extend_from_slice(data);
+        Ok(Cow::Owned(result))
+    }
+
+    unsafe fn pack_payload(
+        &self,
+        state: lua_State,
+        index: i32,
+    ) -> Result<&'static [u8], Box<dyn std::error::Error>> {
+        lua::Lchecktype(state, index, 5);
+        lua::pushnil(state);
+        while lua::next(state, index) != 0 {
+            let known = lua::get_type(state, -2) == 4 && {
+                let key = to_slice(state, -2);
+                self.fields.iter().any(|field| field.name.as_bytes() == key)
+            };
+            if !known {
+                lua::settop(state, -2);
+                let name = match lua::get_type(state, -1) {
+                    4 => String::from_utf8_lossy(to_slice(state, -1)).into_owned(),
+                    _ => String::from("<non-string key>"),
+                };
+                return Err(format!("unknown field '{}'", name).into());
+            }
+            lua::settop(state, -2);
+        }
+
+        let base = lua::gettop(state);
+        lua::Lcheckstack(state, self.fmt.len() as _, lua::cstr!("too many fields"));
+        let mut option = 0;
+        for field in self.fields.iter() {
+            lua::getfield(state, index, field.name.as_ptr());
+            if field.count == 1 {
+                self.check_value(state, field, option)?;
+            } else {
+                let value = lua::gettop(state);
+                match lua::get_type(state, value) {
+                    0 | 5 => {}
+                    _ => {
+                        return Err(format!(
+                            "field '{}': table expected",
+                            field.name.to_string_lossy()
+                        )
+                        .into())
+                    }
+                }
+                for i in 0..field.count {
+                    if lua::get_type(state, value) == 5 {
+                        lua::rawgeti(state, value, i as i32 + 1);
+                    } else {
+                        lua::pushnil(state);
+                    }
+                    self.check_value(state, field, option + i)?;
+                }
+                lua::remove(state, value);
+            }
+            option += field.count;
+        }
+        let result = lua_struct::pack(state, &self.fmt, base + 1);
+        lua::settop(state, base);
+        Ok(struct_try!(state, result))
+    }
+
+    /// Runs registered migration if `data` was stored with older version.
+    /// Returns record repacked in current version.
+    pub unsafe fn upgrade(
+        &self,
+        state: lua_State,
+        data: &[u8],
+    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        let (version, payload) = self.split_header(data);
+        if version == self.version {
+            return Ok(None);
+        }
+        if version > self.version {
+            return Err(format!(
+                "record version {} is newer than schema version {}",
+                version, self.version
+            )
+            .into());
+        }
+        let migration = match self.migrations.iter().find(|m| m.from == version) {
+            Some(migration) => migration,
+            None => return Err(format!("no migration from version {}", version).into()),
+        };
+        lua::rawgeti(state, lua::REGISTRYINDEX, migration.func);
+        lua::rawgeti(state, lua::REGISTRYINDEX, migration.schema);
+        let old = &*lua::touserdata(state, -1).cast::<Self>();
+        old.unpack_payload(state, payload)?;
+        lua::remove(state, -2);
+        let top = lua::gettop(state) - 1;
+        match lua::pcall(state, 1, 1, 0) {
+            lua::Status::Ok => {}
+            _ => {
+                let mut len = 0;
+                let message = lua::tolstring(state, top, &mut len);
+                let message = if message.is_null() {
+                    String::from("(error object is not a string)")
+                } else {
+                    String::from_utf8_lossy(std::slice::from_raw_parts(message, len)).into_owned()
+                };
+                lua::settop(state, top - 1);
+                return Err(
+                    format!("migration from version {} failed: {}", version, message).into(),
+                );
+            }
+        }
+        if lua::get_type(state, top) != 5 {
+            lua::settop(state, top - 1);
+            return Err(format!("migration from version {} must return a table", version).into());
+        }
+        let result = self.pack(state, top)?.into_owned();
+        lua::settop(state, top - 1);
+        Ok(Some(result))
+    }
+
+    /// Reads record under `key` through `codec`, migrating it to current version if needed.
+    /// With `writeback` migrated record is stored back unless it was changed meanwhile.
+    pub unsafe fn get(
+        &self,
+        state: lua_State,
+        tree: &sled::Tree,
+        codec: &Codec,
+        key: &[u8],
+        writeback: bool,
+    ) -> Result<Option<sled::IVec>, Box<dyn std::error::Error>> {
+        let stored = match tree.get(key)? {
+            Some(stored) => stored,
+            None => return Ok(None),
+        };
+        let value = codec.decode(key, stored.clone())?;
+        match self.upgrade(state, &value)? {
+            Some(upgraded) => {
+                if writeback {
+                    let encoded = codec.encode(key, &upgraded)?.into_owned();
+                    let _ = tree.compare_and_swap(key, Some(&stored), Some(encoded))?;
+                }
+                Ok(Some(upgraded.into()))
+            }
+            None => Ok(Some(value)),
+        }
+    }
+
+    fn check_version<'a>(&self, data: &'a [u8]) -> Result<&'a [u8], Box<dyn std::error::Error>> {
+        match self.split_header(data) {
+            (version, payload) if version == self.version => Ok(payload),
+            (version, _) => Err(format!(
+                "record version {} doesn't match schema version {}",
+                version, self.version
+            )
+            .into()),
+        }
+    }
+
+    /// Pushes table with unpacked fields of current version record.
+    pub unsafe fn unpack(
+        &self,
+        state: lua_State,
+        data: &[u8],
+    ) -> Result<i32, Box<dyn std::error::Error>> {
+        self.unpack_payload(state, self.check_version(data)?)
+    }
+
+    /// Pushes unpacked fields of current version record on the stack, in declaration order.
+    pub unsafe fn unpack_values(
+        &self,
+        state: lua_State,
+        data: &[u8],
+    ) -> Result<i32, Box<dyn std::error::Error>> {
+        let payload = self.check_version(data)?;
+        Ok(struct_try!(
+            state,
+            lua_struct::unpack(state, &self.fmt, payload)
+        ))
+    }
+
+    unsafe fn unpack_payload(
+        &self,
+        state: lua_State,
+        data: &[u8],
+    ) -> Result<i32, Box<dyn std::error::Error>> {
+        lua::Lcheckstack(
+            state,
+            self.fmt.len() as i32 + 2,
+            lua::cstr!("too many fields"),
+        );
+        lua::createtable(state, 0, self.fields.len() as _);
+        let table = lua::gettop(state);
+        struct_try!(state, lua_struct::unpack(state, &self.fmt, data));
+        let mut value = table + 1;
+        for field in self.fields.iter() {
+            if field.count == 1 {
+                lua::pushvalue(state, value);
+            } else {
+                lua::createtable(state, field.count as _, 0);
+                for i in 0..field.count as i32 {
+                    lua::pushvalue(state, value + i);
+                    lua::rawseti(state, -2, i + 1);
+                }
+            }
+            lua::setfield(state, table, field.name.as_ptr());
+            value += field.count as i32;
+        }
+        lua::settop(state, table);
+        Ok(1)
+    }
+
+    /// Reads field list at `index`. Errors are returned rather than raised,
+    /// so fields collected so far are dropped before lua unwinds the stack.
+    unsafe fn from_table(
+        state: lua_State,
+        index: i32,
+        version: u16,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut schema = Self {
+            fields: Vec::new(),
+            fmt: Struct::default(),
+            version,
+            migrations: Vec::new(),
+        };
+        for i in 1..=lua::objlen(state, index) as i32 {
+            lua::rawgeti(state, index, i);
+            if lua::get_type(state, -1) != 5 {
+                return Err(format!("field #{}: table expected", i).into());
+            }
+            lua::rawgeti(state, -1, 1);
+            lua::rawgeti(state, -2, 2);
+            if lua::get_type(state, -2) != 4 || lua::get_type(state, -1) != 4 {
+                return Err(format!("field #{}: {{name, format}} expected", i).into());
+            }
+            let fmt = lua_struct::compile(to_slice(state, -1))?;
+            let name = CString::new(to_slice(state, -2))?;
+            lua::settop(state, -4);
+            if fmt.len() == 0 {
+                return Err(format!("field '{}': empty format", name.to_string_lossy()).into());
+            }
+            if schema.fields.iter().any(|field| field.name == name) {
+                return Err(format!("duplicate field '{}'", name.to_string_lossy()).into());
+            }
+            schema.fields.push(SchemaField {
+                name,
+                count: fmt.len(),
+            });
+            schema.fmt.append(fmt);
+        }
+        Ok(schema)
+    }
+
+    pub fn l_new(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            lua::Lchecktype(state, 1, 5);
+            let version = match lua::Loptinteger(state, 2, 0) {
+                version @ 0..=0xFFFF => version as u16,
+                _ => lua::Largerror(state, 2, lua::cstr!("version out of range [0,65535]")),
+            };
+            let schema = Self::from_table(state, 1, version)?;
+            let udata = lua::newuserdata(state, std::mem::size_of::<Self>()).cast::<Self>();
+            udata.write(schema);
+            Self::metatable(state);
+            lua::setmetatable(state, -2);
+        }
+        Ok(1)
+    }
+
+    fn lm_pack(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &*lua::Lcheckudata(state, 1, lua::cstr!("cslsc")).cast::<Self>();
+            let data = this.pack(state, 2)?;
+            lua::pushlstring(state, data.as_ptr(), data.len());
+            Ok(1)
+        }
+    }
+
+    fn lm_unpack(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &*lua::Lcheckudata(state, 1, lua::cstr!("cslsc")).cast::<Self>();
+            let data = check_slice!(state, 2);
+            match this.upgrade(state, data)? {
+                Some(upgraded) => this.unpack(state, &upgraded),
+                None => this.unpack(state, data),
+            }
+        }
+    }
+
+    fn lm_version(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &*lua::Lcheckudata(state, 1, lua::cstr!("cslsc")).cast::<Self>();
+            if lua::get_type(state, 2) > 0 {
+                let (version, _) = this.split_header(check_slice!(state, 2));
+                lua::pushinteger(state, version as _);
+            } else {
+                lua::pushinteger(state, this.version as _);
+            }
+            Ok(1)
+        }
+    }
+
+    fn lm_add_migration(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = &mut *lua::Lcheckudata(state, 1, lua::cstr!("cslsc")).cast::<Self>();
+            let from = lua::Lcheckinteger(state, 2);
+            if from < 0 || from >= this.version as _ {
+                lua::Largerror(
+                    state,
+                    2,
+                    lua::cstr!("version must be lower than schema version"),
+                );
+            }
+            lua::Lcheckudata(state, 3, lua::cstr!("cslsc"));
+            lua::Lchecktype(state, 4, 6);
+            lua::settop(state, 4);
+            let func = lua::Lref(state, lua::REGISTRYINDEX);
+            let schema = lua::Lref(state, lua::REGISTRYINDEX);
+            let migration = Migration {
+                from: from as u16,
+                schema,
+                func,
+            };
+            if let Some(old) = this
+                .migrations
+                .iter_mut()
+                .find(|m| m.from == migration.from)
+            {
+                lua::Lunref(state, lua::REGISTRYINDEX, old.schema);
+                lua::Lunref(state, lua::REGISTRYINDEX, old.func);
+                *old = migration;
+            } else {
+                this.migrations.push(migration);
+            }
+            Ok(0)
+        }
+    }
+
+    fn __gc(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+        unsafe {
+            let this = lua::Lcheckudata(state, 1, lua::cstr!("cslsc")).cast::<Self>();
+            for migration in (*this).migrations.iter() {
+                lua::Lunref(state, lua::REGISTRYINDEX, migration.schema);
+                lua::Lunref(state, lua::REGISTRYINDEX, migration.func);
+            }
+            this.drop_in_place();
+            Ok(0)
+        }
+    }
+
+    pub unsafe fn metatable(state: lua_State) {
+        if lua::Lnewmetatable(state, lua::cstr!("cslsc")) {
+            lua::pushvalue(state, -1);
+            lua::setfield(state, -2, lua::cstr!("__index"));
+            insert_function!(state, "__gc", Self::__gc);
+            insert_function!(state, "Pack", Self::lm_pack);
+            insert_function!(state, "Unpack", Self::lm_unpack);
+            insert_function!(state, "Version", Self::lm_version);
+            insert_function!(state, "AddMigration", Self::lm_add_migration);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema(version: u16, migrations: &[u16]) -> Schema {
+        Schema {
+            fields: Vec::new(),
+            fmt: Struct::default(),
+            version,
+            migrations: migrations
+                .iter()
+                .map(|&from| Migration {
+                    from,
+                    schema: 0,
+                    func: 0,
+                })
+                .collect(),
+        }
+    }
+
+    fn record(version: u16, payload: &[u8]) -> Vec<u8> {
+        let mut data = VERSION_MAGIC.to_vec();
+        data.extend_from_slice(&version.to_le_bytes());
+        data.extend_from_slice(payload);
+        data
+    }
+
+    #[test]
+    fn split_header_current_version() {
+        let data = record(2, b"abc");
+        assert_eq!(schema(2, &[]).split_header(&data), (2, &b"abc"[..]));
+    }
+
+    #[test]
+    fn split_header_migrated_and_newer_versions() {
+        let schema = schema(3, &[1]);
+        assert_eq!(schema.split_header(&record(1, b"x")), (1, &b"x"[..]));
+        assert_eq!(schema.split_header(&record(7, b"x")), (7, &b"x"[..]));
+    }
+
+    #[test]
+    fn split_header_unknown_version_is_legacy() {
+        let data = record(2, b"x");
+        assert_eq!(schema(3, &[1]).split_header(&data), (0, &data[..]));
+        let data = record(0, b"x");
+        assert_eq!(schema(3, &[0]).split_header(&data), (0, &data[..]));
+    }
+
+    #[test]
+    fn split_header_legacy_data() {
+        // Used to be taken for a version header.
+        let data = [0xFE, 0x01, 0x00, 0x10];
+        assert_eq!(schema(1, &[]).split_header(&data), (0, &data[..]));
+        assert_eq!(schema(1, &[]).split_header(b""), (0, &b""[..]));
+        let data = record(1, b"x");
+        assert_eq!(schema(0, &[]).split_header(&data), (0, &data[..]));
+    }
+}
@@ -0,0 +1,497 @@
+use std::collections::HashMap;
+use std::os::raw::c_void;
+
+use lua_shared as lua;
+use lua_shared::lua_State;
+
+use crate::lua_struct::{self, MAX_VARINT_SIZE};
+use crate::{check_slice, gmod};
+
+// Every value starts with one of these tags.
+const NIL: u8 = 0;
+const FALSE: u8 = 1;
+const TRUE: u8 = 2;
+// Integral numbers, zigzag varint.
+const INTEGER: u8 = 3;
+const NUMBER: u8 = 4;
+const STRING: u8 = 5;
+// Array length and array items, then amount of other pairs and pairs themselves.
+const TABLE: u8 = 6;
+// Index of already written table, counting from 1 in order of appearance.
+const REFERENCE: u8 = 7;
+const VECTOR: u8 = 8;
+const ANGLE: u8 = 9;
+
+const MAX_DEPTH: usize = 128;
+
+fn write_varint(out: &mut Vec<u8>, value: u64) {
+    let mut varint = [0; MAX_VARINT_SIZE];
+    out.extend_from_slice(lua_struct::write_varint(value, &mut varint));
+}
+
+fn type_name(typ: i32) -> &'static str {
+    match typ {
+        2 => "lightuserdata",
+        6 => "function",
+        7 => "userdata",
+        8 => "thread",
+        _ => "value",
+    }
+}
+
+/// Serializes value at absolute stack `index` into `out`.
+pub unsafe fn write_value(
+    state: lua_State,
+    index: i32,
+    out: &mut Vec<u8>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    write(state, index, out, &mut HashMap::new(), 0)
+}
+
+unsafe fn write(
+    state: lua_State,
+    index: i32,
+    out: &mut Vec<u8>,
+    tables: &mut HashMap<*const c_void, u64>,
+    depth: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match lua::get_type(state, index) {
+        -1 | 0 => out.push(NIL),
+        1 => out.push(if lua::toboolean(state, index) as u8 != 0 {
+            TRUE
+        } else {
+            FALSE
+        }),
+        3 => {
+            let value = lua::tonumber(state, index);
+            // -0.0 would lose its sign as an integer.
+            if value.fract() == 0.0
+                && value.abs() < 9.0e18
+                && !(value == 0.0 && value.is_sign_negative())
+            {
+                out.push(INTEGER);
+                write_varint(out, lua_struct::zigzag_encode(value as i64));
+            } else {
+                out.push(NUMBER);
+                out.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        4 => {
+            let mut len = 0;
+            let str = lua::tolstring(state, index, &mut len);
+            out.push(STRING);
+            write_varint(out, len as _);
+            out.extend_from_slice(std::slice::from_raw_parts(str, len));
+        }
+        5 => {
+            let pointer = lua::topointer(state, index);
+            if let Some(&id) = tables.get(&pointer) {
+                out.push(REFERENCE);
+                write_varint(out, id);
+                return Ok(());
+            }
+            if depth >= MAX_DEPTH {
+                return Err("table nesting is too deep".into());
+            }
+            tables.insert(pointer, tables.len() as u64 + 1);
+            lua::Lcheckstack(state, 3, lua::cstr!("table nesting is too deep"));
+            let top = lua::gettop(state);
+            let mut len = 0;
+            loop {
+                lua::rawgeti(state, index, len + 1);
+                let present = lua::get_type(state, -1) > 0;
+                lua::settop(state, top);
+                if !present {
+                    break;
+                }
+                len += 1;
+            }
+            out.push(TABLE);
+            write_varint(out, len as _);
+            for i in 1..=len {
+                lua::rawgeti(state, index, i);
+                let result = write(state, top + 1, out, tables, depth + 1);
+                lua::settop(state, top);
+                result?;
+            }
+            let is_array_key = |state: lua_State| {
+                let key = lua::tonumber(state, top + 1);
+                lua::get_type(state, top + 1) == 3
+                    && key.fract() == 0.0
+                    && key >= 1.0
+                    && key <= len as f64
+            };
+            let mut pairs = 0;
+            lua::pushnil(state);
+            while lua::next(state, index) != 0 {
+                if !is_array_key(state) {
+                    pairs += 1;
+                }
+                lua::settop(state, top + 1);
+            }
+            write_varint(out, pairs);
+            lua::pushnil(state);
+            while lua::next(state, index) != 0 {
+                if !is_array_key(state) {
+                    let result = write(state, top + 1, out, tables, depth + 1)
+                        .and_then(|_| write(state, top + 2, out, tables, depth + 1));
+                    if result.is_err() {
+                        lua::settop(state, top);
+                        return result;
+                    }
+                }
+                lua::settop(state, top + 1);
+            }
+        }
+        7 if gmod::is_vector(state, index) || gmod::is_angle(state, index) => {
+            let value = if gmod::is_vector(state, index) {
+                out.push(VECTOR);
+                gmod::check_vector(state, index)
+            } else {
+                out.push(ANGLE);
+                gmod::check_angle(state, index)
+            };
+            for component in value {
+                out.extend_from_slice(&(component as f32).to_le_bytes());
+            }
+        }
+        typ => return Err(format!("cannot serialize {}", type_name(typ)).into()),
+    }
+    Ok(())
+}
+
+/// Deserializes one value from `data` and pushes it, returns amount of consumed bytes.
+pub unsafe fn read_value(
+    state: lua_State,
+    data: &[u8],
+) -> Result<usize, Box<dyn std::error::Error>> {
+    // Malformed data is rejected before any lua value is created.
+    let mut reader = Reader {
+        data,
+        pos: 0,
+        tables: 0,
+        count: 0,
+    };
+    reader.skip(0)?;
+    // Tables already read, for references.
+    lua::createtable(state, 0, 0);
+    let tables = lua::gettop(state);
+    let mut reader = Reader {
+        data,
+        pos: 0,
+        tables,
+        count: 0,
+    };
+    let result = reader.read(state, 0);
+    match result {
+        Ok(()) => {
+            lua::remove(state, tables);
+            Ok(reader.pos)
+        }
+        Err(e) => {
+            lua::settop(state, tables - 1);
+            Err(e)
+        }
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    tables: i32,
+    count: i32,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Box<dyn std::error::Error>> {
+        if self.data.len() - self.pos < len {
+            return Err("data string too short".into());
+        }
+        self.pos += len;
+        Ok(&self.data[self.pos - len..self.pos])
+    }
+
+    fn varint(&mut self) -> Result<u64, Box<dyn std::error::Error>> {
+        match lua_struct::read_varint(&self.data[self.pos..]) {
+            Some((value, len)) => {
+                self.pos += len;
+                Ok(value)
+            }
+            None => Err("data string too short".into()),
+        }
+    }
+
+    fn components(&mut self) -> Result<[f64; 3], Box<dyn std::error::Error>> {
+        let mut value = [0.0; 3];
+        for component in value.iter_mut() {
+            *component = f32::from_le_bytes(self.bytes(4)?.try_into()?) as f64;
+        }
+        Ok(value)
+    }
+
+    // Walks one value the same way `read` does, without lua.
+    fn skip(&mut self, depth: usize) -> Result<(), Box<dyn std::error::Error>> {
+        if depth > MAX_DEPTH {
+            return Err("table nesting is too deep".into());
+        }
+        match self.bytes(1)?[0] {
+            NIL | FALSE | TRUE => {}
+            INTEGER => {
+                self.varint()?;
+            }
+            NUMBER => {
+                self.bytes(8)?;
+            }
+            STRING => {
+                let len = usize::try_from(self.varint()?)?;
+                self.bytes(len)?;
+            }
+            TABLE => {
+                self.count += 1;
+                for _ in 0..self.varint()? {
+                    self.skip(depth + 1)?;
+                }
+                for _ in 0..self.varint()? {
+                    let key = self.pos;
+                    self.skip(depth + 1)?;
+                    let invalid = match self.data[key] {
+                        NIL => true,
+                        NUMBER => {
+                            f64::from_le_bytes(self.data[key + 1..key + 9].try_into()?).is_nan()
+                        }
+                        _ => false,
+                    };
+                    if invalid {
+                        return Err("invalid table key".into());
+                    }
+                    self.skip(depth + 1)?;
+                }
+            }
+            REFERENCE => {
+                let id = self.varint()?;
+                if id == 0 || id > self.count as u64 {
+                    return Err("invalid table reference".into());
+                }
+            }
+            VECTOR | ANGLE => {
+                self.components()?;
+            }
+            tag => return Err(format!("invalid value tag {}", tag).into()),
+        }
+        Ok(())
+    }
+
+    unsafe fn read(
+        &mut self,
+        state: lua_State,
+        depth: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if depth > MAX_DEPTH || lua::checkstack(state, 4) == 0 {
+            return Err("table nesting is too deep".into());
+        }
+        match self.bytes(1)?[0] {
+            NIL => lua::pushnil(state),
+            FALSE => lua::pushboolean(state, 0),
+            TRUE => lua::pushboolean(state, 1),
+            INTEGER => {
+                let value = lua_struct::zigzag_decode(self.varint()?);
+                lua::pushnumber(state, value as _);
+            }
+            NUMBER => {
+                let value = f64::from_le_bytes(self.bytes(8)?.try_into()?);
+                lua::pushnumber(state, value);
+            }
+            STRING => {
+                let len = usize::try_from(self.varint()?)?;
+                let str = self.bytes(len)?;
+                lua::pushlstring(state, str.as_ptr(), str.len());
+            }
+            TABLE => {
+                let len = self.varint()?;
+                // Don't trust sizes for preallocation, every item takes at least one byte.
+                let remaining = (self.data.len() - self.pos) as u64;
+                lua::createtable(state, len.min(remaining) as _, 0);
+                let table = lua::gettop(state);
+                self.count += 1;
+                lua::pushvalue(state, table);
+                lua::rawseti(state, self.tables, self.count);
+                for i in 1..=len {
+                    self.read(state, depth + 1)?;
+                    lua::rawseti(state, table, i as _);
+                }
+                for _ in 0..self.varint()? {
+                    self.read(state, depth + 1)?;
+                    let key = lua::get_type(state, -1);
+                    if key == 0 || (key == 3 && lua::tonumber(state, -1).is_nan()) {
+                        return Err("invalid table key".into());
+                    }
+                    self.read(state, depth + 1)?;
+                    lua::rawset(state, table);
+                }
+            }
+            REFERENCE => {
+                let id = self.varint()?;
+                if id == 0 || id > self.count as u64 {
+                    return Err("invalid table reference".into());
+                }
+                lua::rawgeti(state, self.tables, id as _);
+            }
+            VECTOR => gmod::push_vector(state, self.components()?),
+            ANGLE => gmod::push_angle(state, self.components()?),
+            tag => return Err(format!("invalid value tag {}", tag).into()),
+        }
+        Ok(())
+    }
+}
+
+/// `sled.Serialize(value)`
+pub fn l_serialize(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+    unsafe {
+        let mut out = Vec::new();
+        write_value(state, 1, &mut out)?;
+        lua::pushlstring(state, out.as_ptr(), out.len());
+        Ok(1)
+    }
+}
+
+/// `sled.Deserialize(data)`
+pub fn l_deserialize(state: lua_State) -> Result<i32, Box<dyn std::error::Error>> {
+    unsafe {
+        let data = check_slice!(state, 1);
+        let consumed = read_value(state, data)?;
+        if consumed < data.len() {
+            return Err(format!("{} trailing bytes", data.len() - consumed).into());
+        }
+        Ok(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reader() {
+        let mut data = vec![0x96, 0x01, 1, 2];
+        for component in [1.0f32, 2.0, -0.5] {
+            data.extend_from_slice(&component.to_le_bytes());
+        }
+        let mut reader = Reader {
+            data: &data,
+            pos: 0,
+            tables: 0,
+            count: 0,
+        };
+        assert_eq!(reader.varint().unwrap(), 150);
+        assert_eq!(reader.bytes(2).unwrap(), [1, 2]);
+        assert_eq!(reader.components().unwrap(), [1.0, 2.0, -0.5]);
+        assert!(reader.bytes(1).is_err());
+        assert!(reader.varint().is_err());
+        assert_eq!(reader.pos, data.len());
+    }
+
+    fn skip(data: &[u8]) -> Result<usize, String> {
+        let mut reader = Reader {
+            data,
+            pos: 0,
+            tables: 0,
+            count: 0,
+        };
+        match reader.skip(0) {
+            Ok(()) => Ok(reader.pos),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    // `n` tables, each one holding the next in its array part.
+    fn nested(n: usize) -> Vec<u8> {
+        let mut data = [TABLE, 1].repeat(n - 1);
+        data.extend_from_slice(&[TABLE, 0, 0]);
+        data.extend(std::iter::repeat(0).take(n - 1));
+        data
+    }
+
+    #[test]
+    fn skip_scalars() {
+        let mut vector = vec![VECTOR];
+        vector.extend_from_slice(&[0; 12]);
+        let mut number = vec![NUMBER];
+        number.extend_from_slice(&1.5f64.to_le_bytes());
+        for data in [
+            &[NIL][..],
+            &[TRUE],
+            &[INTEGER, 0x96, 0x01],
+            &number[..],
+            &[STRING, 2, b'h', b'i'],
+            &vector[..],
+        ] {
+            assert_eq!(skip(data), Ok(data.len()));
+        }
+        assert_eq!(skip(&[NIL, NIL]), Ok(1));
+        for data in [
+            &[][..],
+            &[42],
+            &[INTEGER],
+            &[NUMBER, 0, 0],
+            &[STRING, 5, b'h'],
+            &[VECTOR, 0, 0, 0, 0],
+        ] {
+            assert!(skip(data).is_err(), "{:?}", data);
+        }
+    }
+
+    #[test]
+    fn skip_tables() {
+        // {2, "a", k = true}
+        let data = [
+            TABLE, 2, INTEGER, 4, STRING, 1, b'a', 1, STRING, 1, b'k', TRUE,
+        ];
+        assert_eq!(skip(&data), Ok(data.len()));
+        assert!(skip(&data[..data.len() - 1]).is_err());
+        // Array length is not trusted.
+        assert!(skip(&[TABLE, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F, NIL]).is_err());
+
+        let mut nan = vec![TABLE, 0, 1, NUMBER];
+        nan.extend_from_slice(&f64::NAN.to_le_bytes());
+        nan.push(TRUE);
+        assert_eq!(skip(&nan), Err(String::from("invalid table key")));
+        assert_eq!(
+            skip(&[TABLE, 0, 1, NIL, TRUE]),
+            Err(String::from("invalid table key"))
+        );
+    }
+
+    #[test]
+    fn skip_references() {
+        // t.self = t
+        let cycle = [TABLE, 0, 1, STRING, 4, b's', b'e', b'l', b'f', REFERENCE, 1];
+        assert_eq!(skip(&cycle), Ok(cycle.len()));
+        // {u, u}, second item refers to the first one.
+        let shared = [TABLE, 2, TABLE, 0, 0, REFERENCE, 2, 0];
+        assert_eq!(skip(&shared), Ok(shared.len()));
+
+        for data in [
+            &[REFERENCE, 1][..],
+            &[TABLE, 1, REFERENCE, 0, 0],
+            &[TABLE, 1, REFERENCE, 2, 0],
+            &[TABLE, 2, REFERENCE, 2, TABLE, 0, 0, 0],
+        ] {
+            assert_eq!(
+                skip(data),
+                Err(String::from("invalid table reference")),
+                "{:?}",
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn skip_depth_limit() {
+        let data = nested(MAX_DEPTH + 1);
+        assert_eq!(skip(&data), Ok(data.len()));
+        assert_eq!(
+            skip(&nested(MAX_DEPTH + 2)),
+            Err(String::from("table nesting is too deep"))
+        );
+    }
+}